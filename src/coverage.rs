@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+use crate::Error;
+use crate::evaluator::Vm;
+use crate::parser::{InstructionKind, ParserRet, ValueType};
+
+pub struct Coverage {
+    pub hits: Vec<u64>,
+    // Times each `jnz`/`jzr` jumped and fell through.
+    pub branches: Vec<[u64; 2]>,
+    // Times each function was entered, at the index of its `fun`.
+    pub calls: Vec<u64>,
+}
+
+// Runs `vm` to completion, counting how often every instruction executed and which way every
+// conditional jump went.
+pub fn cover(vm: &mut Vm) -> Result<(i32, Coverage), Error> {
+    let mut coverage = Coverage {
+        hits: vec![0; vm.program.instrs.len()],
+        branches: vec![[0, 0]; vm.program.instrs.len()],
+        calls: vec![0; vm.program.instrs.len()],
+    };
+
+    loop {
+        let index = vm.cur;
+        if index >= vm.program.instrs.len() {
+            return Ok((vm.step()?.unwrap_or(0), coverage));
+        }
+
+        let jumps = match vm.program.instrs[index].kind {
+            InstructionKind::Jnz => vm.stack.last().and_then(|v| v.to_bool().ok()),
+            InstructionKind::Jzr => vm.stack.last().and_then(|v| v.to_bool().ok()).map(|truthy| !truthy),
+            _ => None,
+        };
+
+        let calls = vm.ret_stack.len();
+        let exit = vm.step()?;
+        coverage.hits[index] += 1;
+        // `run` continues after the callee's `fun`, which only executes when a run starts there.
+        match vm.program.instrs[index].kind {
+            InstructionKind::Fun => coverage.calls[index] += 1,
+            InstructionKind::Run if vm.ret_stack.len() > calls => coverage.calls[vm.cur - 1] += 1,
+            _ => {},
+        }
+        if let Some(jumps) = jumps {
+            coverage.branches[index][if jumps { 0 } else { 1 }] += 1;
+        }
+
+        if let Some(code) = exit {
+            return Ok((code, coverage));
+        }
+    }
+}
+
+#[derive(Default)]
+struct Record {
+    funcs: Vec<(usize, String, u64)>,
+    branches: Vec<(usize, usize, Option<[u64; 2]>)>,
+    lines: BTreeMap<usize, u64>,
+}
+
+fn write_record(out: &mut String, path: &str, record: &Record) {
+    out.push_str(&format!("TN:\nSF:{}\n", path));
+
+    for (line, name, _) in &record.funcs {
+        out.push_str(&format!("FN:{},{}\n", line, name));
+    }
+    for (_, name, count) in &record.funcs {
+        out.push_str(&format!("FNDA:{},{}\n", count, name));
+    }
+    out.push_str(&format!("FNF:{}\nFNH:{}\n", record.funcs.len(), record.funcs.iter().filter(|f| f.2 > 0).count()));
+
+    let mut taken = 0;
+    for (line, block, counts) in &record.branches {
+        for branch in 0..2 {
+            let count = match counts {
+                Some(counts) => {
+                    if counts[branch] > 0 {
+                        taken += 1;
+                    }
+                    counts[branch].to_string()
+                },
+                None => "-".to_string(),
+            };
+            out.push_str(&format!("BRDA:{},{},{},{}\n", line, block, branch, count));
+        }
+    }
+    out.push_str(&format!("BRF:{}\nBRH:{}\n", record.branches.len() * 2, taken));
+
+    for (line, count) in &record.lines {
+        out.push_str(&format!("DA:{},{}\n", line, count));
+    }
+    out.push_str(&format!("LF:{}\nLH:{}\n", record.lines.len(), record.lines.values().filter(|c| **c > 0).count()));
+    out.push_str("end_of_record\n");
+}
+
+// Renders coverage in lcov format, with one record per `.zvm` file (instructions without a file are
+// attributed to `default_path`) followed by one record per Zelkel source file named by debug symbols.
+// An instruction maps to the source line of the last debug symbol before it in its function.
+pub fn lcov(program: &ParserRet, coverage: &Coverage, default_path: &str) -> String {
+    let mut zvm: BTreeMap<String, Record> = BTreeMap::new();
+    let mut source: BTreeMap<String, Record> = BTreeMap::new();
+    let mut debug_symbol = None;
+
+    for (index, instr) in program.instrs.iter().enumerate() {
+        let path = instr.file.clone().unwrap_or(default_path.to_string());
+        let hits = coverage.hits[index];
+
+        match (&instr.kind, &instr.params.first()) {
+            (InstructionKind::Fun, Some(name)) => {
+                debug_symbol = None;
+                zvm.entry(path).or_default().funcs.push((instr.line, name.to_string(), coverage.calls[index]));
+                continue;
+            },
+            (InstructionKind::DebugSymbol, Some(ValueType::DebugSymbol(ds))) => {
+                debug_symbol = Some(ds.clone());
+                source.entry(ds.path.clone()).or_default().lines.entry(ds.line).or_insert(0);
+            },
+            (InstructionKind::Lbl, _) => continue,
+            _ => {},
+        }
+
+        let branch = match instr.kind {
+            InstructionKind::Jnz | InstructionKind::Jzr => Some(if hits > 0 { Some(coverage.branches[index]) } else { None }),
+            _ => None,
+        };
+
+        let record = zvm.entry(path).or_default();
+        let count = record.lines.entry(instr.line).or_insert(0);
+        *count = (*count).max(hits);
+        if let Some(counts) = branch {
+            record.branches.push((instr.line, index, counts));
+        }
+
+        if let Some(ds) = &debug_symbol {
+            if instr.kind == InstructionKind::DebugSymbol {
+                continue;
+            }
+            let record = source.entry(ds.path.clone()).or_default();
+            let count = record.lines.entry(ds.line).or_insert(0);
+            *count = (*count).max(hits);
+            if let Some(counts) = branch {
+                record.branches.push((ds.line, index, counts));
+            }
+        }
+    }
+
+    let mut out = String::new();
+    for (path, record) in zvm.iter().chain(source.iter()) {
+        write_record(&mut out, path, record);
+    }
+    out
+}
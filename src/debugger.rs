@@ -0,0 +1,237 @@
+use std::io::{BufRead, Write};
+use crate::disasm;
+use crate::snapshot;
+use crate::evaluator::Vm;
+use crate::parser::{InstructionKind, ValueType};
+
+const HELP: &str = "Commands:
+  s, step [n]         execute n instructions (default 1)
+  c, continue         run until a breakpoint or the end of the program
+  b, break <target>   break at .label, @function, line, file:line or <path:line[:col]>
+  d, delete <n>       remove breakpoint n
+  breaks              list breakpoints
+  w, where            show the next instruction and the call stack
+  l, list [n]         show n instructions around the next one (default 5)
+  stack               show the stack
+  vars                show variables
+  bufs                show buffers
+  rets                show the return stack
+  save <path>         write a snapshot of the VM to path
+  q, quit             stop debugging
+  h, help             show this help";
+
+enum Breakpoint {
+    Index(usize),
+    Line(Option<String>, usize),
+    Source(String, usize, Option<usize>),
+}
+
+fn parse_breakpoint(vm: &Vm, target: &str) -> Result<(Breakpoint, String), String> {
+    if target.starts_with('.') || target.starts_with('@') {
+        let index = if target.starts_with('.') { vm.program.labels.get(target) } else { vm.program.funcs.get(target) };
+        // Jumps and calls continue after the label itself, so stop at the first instruction of the body.
+        let index = index.ok_or(format!("{} not found", target))? + 1;
+        return Ok((Breakpoint::Index(index), format!("{} (#{})", target, index)));
+    }
+
+    if let Some(source) = target.strip_prefix('<').and_then(|t| t.strip_suffix('>')) {
+        let parts: Vec<&str> = source.split(':').collect();
+        let number = |s: &str| s.parse::<usize>().map_err(|_| format!("Invalid source location: {}", target));
+        return match parts.as_slice() {
+            [path, line] => Ok((Breakpoint::Source(path.to_string(), number(line)?, None), target.to_string())),
+            [path, line, col] => Ok((Breakpoint::Source(path.to_string(), number(line)?, Some(number(col)?)), target.to_string())),
+            _ => Err(format!("Invalid source location: {}", target)),
+        };
+    }
+
+    let (file, line) = match target.rsplit_once(':') {
+        Some((file, line)) => (Some(file.to_string()), line),
+        None => (None, target),
+    };
+    let line = line.parse::<usize>().map_err(|_| format!("Invalid breakpoint: {}", target))?;
+    Ok((Breakpoint::Line(file, line), target.to_string()))
+}
+
+fn hits(vm: &Vm, breakpoint: &Breakpoint) -> bool {
+    let Some(instr) = vm.program.instrs.get(vm.cur) else {
+        return false;
+    };
+
+    match breakpoint {
+        Breakpoint::Index(index) => vm.cur == *index,
+        Breakpoint::Line(file, line) => {
+            instr.line == *line && file.as_ref().is_none_or(|f| instr.file.as_ref().is_some_and(|i| i.ends_with(f.as_str())))
+        },
+        Breakpoint::Source(path, line, col) => match &instr.params.first() {
+            Some(ValueType::DebugSymbol(ds)) if instr.kind == InstructionKind::DebugSymbol => {
+                ds.path == *path && ds.line == *line && col.is_none_or(|c| ds.col == c)
+            },
+            _ => false,
+        },
+    }
+}
+
+fn location(vm: &Vm, index: usize) -> String {
+    let instr = &vm.program.instrs[index];
+    match &instr.file {
+        Some(file) => format!("{}:{}:{}", file, instr.line, instr.col),
+        None => format!("{}:{}", instr.line, instr.col),
+    }
+}
+
+fn describe(vm: &Vm, index: usize) -> String {
+    // Programs without a final `ret` run off their last instruction.
+    let Some(instr) = vm.program.instrs.get(index) else {
+        return "Program finished".to_string();
+    };
+    let mut line = format!("#{} {} at {}", index, disasm::render(instr), location(vm, index));
+    if let Some(ds) = &vm.current_debug_symbol {
+        line.push_str(&format!(" ({}:{}:{})", ds.path, ds.line, ds.col));
+    }
+    line
+}
+
+fn show_where<W: Write>(vm: &Vm, output: &mut W) -> std::io::Result<()> {
+    if vm.cur >= vm.program.instrs.len() {
+        return writeln!(output, "Program finished");
+    }
+
+    writeln!(output, "{}", describe(vm, vm.cur))?;
+    writeln!(output, "  in {}", vm.function_at(vm.cur).unwrap_or("?"))?;
+    for ret in vm.ret_stack.iter().rev() {
+        writeln!(output, "  called from {} at {}", vm.function_at(*ret).unwrap_or("?"), location(vm, *ret))?;
+    }
+    Ok(())
+}
+
+fn show_list<W: Write>(vm: &Vm, around: usize, output: &mut W) -> std::io::Result<()> {
+    let start = vm.cur.saturating_sub(around);
+    let end = (vm.cur + around + 1).min(vm.program.instrs.len());
+    for index in start..end {
+        let marker = if index == vm.cur { "=>" } else { "  " };
+        writeln!(output, "{} {:>4} {}", marker, index, disasm::render(&vm.program.instrs[index]))?;
+    }
+    Ok(())
+}
+
+fn show_state<W: Write>(vm: &Vm, what: &str, output: &mut W) -> std::io::Result<()> {
+    match what {
+        "stack" => writeln!(output, "{:?}", vm.stack)?,
+        "vars" => {
+            let mut vars: Vec<_> = vm.vars.iter().collect();
+            vars.sort_by(|a, b| a.0.cmp(b.0));
+            for (name, value) in vars {
+                writeln!(output, "{} = {}", name, disasm::literal(value))?;
+            }
+        },
+        "bufs" => {
+            let mut bufs: Vec<_> = vm.bufs.iter().collect();
+            bufs.sort_by(|a, b| a.0.cmp(b.0));
+            for (name, buf) in bufs {
+                writeln!(output, "{} [{} bytes] {:?}", name, buf.size, String::from_utf8_lossy(&buf.data))?;
+            }
+        },
+        _ => {
+            for ret in vm.ret_stack.iter().rev() {
+                writeln!(output, "#{} in {}", ret, vm.function_at(*ret).unwrap_or("?"))?;
+            }
+        },
+    }
+    Ok(())
+}
+
+// Executes one instruction, reporting a finished program or a runtime error. Returns false when
+// execution cannot continue.
+fn step<W: Write>(vm: &mut Vm, output: &mut W) -> std::io::Result<bool> {
+    match vm.step() {
+        Ok(Some(code)) => {
+            writeln!(output, "Program exited with code {}", code)?;
+            Ok(false)
+        },
+        Ok(None) => Ok(true),
+        Err(err) => {
+            writeln!(output, "Runtime error: {:?}", err)?;
+            Ok(false)
+        },
+    }
+}
+
+// Runs an interactive debugging session over `vm`, reading commands from `input`. Returns the exit
+// code if the program ran to completion.
+pub fn debug<R: BufRead, W: Write>(vm: &mut Vm, input: R, output: &mut W) -> std::io::Result<Option<i32>> {
+    let mut breakpoints: Vec<(Breakpoint, String)> = Vec::new();
+    let mut running = true;
+
+    show_where(vm, output)?;
+    write!(output, "(zdb) ")?;
+    output.flush()?;
+
+    for line in input.lines() {
+        let line = line?;
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let argument = words.next();
+
+        match command {
+            "" => {},
+            "s" | "step" if running => {
+                let count = argument.and_then(|n| n.parse::<usize>().ok()).unwrap_or(1);
+                for _ in 0..count {
+                    running = step(vm, output)?;
+                    if !running {
+                        break;
+                    }
+                }
+                if running {
+                    writeln!(output, "{}", describe(vm, vm.cur))?;
+                }
+            },
+            "c" | "continue" if running => {
+                running = step(vm, output)?;
+                while running && !breakpoints.iter().any(|(b, _)| hits(vm, b)) {
+                    running = step(vm, output)?;
+                }
+                if running {
+                    let (_, name) = breakpoints.iter().find(|(b, _)| hits(vm, b)).unwrap();
+                    writeln!(output, "Breakpoint {}: {}", name, describe(vm, vm.cur))?;
+                }
+            },
+            "s" | "step" | "c" | "continue" => writeln!(output, "The program is not running")?,
+            "b" | "break" => match argument.map(|a| parse_breakpoint(vm, a)) {
+                Some(Ok(breakpoint)) => {
+                    writeln!(output, "Breakpoint {} at {}", breakpoints.len(), breakpoint.1)?;
+                    breakpoints.push(breakpoint);
+                },
+                Some(Err(err)) => writeln!(output, "{}", err)?,
+                None => writeln!(output, "Usage: break <target>")?,
+            },
+            "d" | "delete" => match argument.and_then(|n| n.parse::<usize>().ok()) {
+                Some(n) if n < breakpoints.len() => {
+                    breakpoints.remove(n);
+                },
+                _ => writeln!(output, "No such breakpoint")?,
+            },
+            "breaks" => {
+                for (n, (_, name)) in breakpoints.iter().enumerate() {
+                    writeln!(output, "{}: {}", n, name)?;
+                }
+            },
+            "w" | "where" => show_where(vm, output)?,
+            "l" | "list" => show_list(vm, argument.and_then(|n| n.parse::<usize>().ok()).unwrap_or(5), output)?,
+            "stack" | "vars" | "bufs" | "rets" => show_state(vm, command, output)?,
+            "save" => match argument.map(|path| std::fs::write(path, snapshot::save(vm))) {
+                Some(Ok(())) => writeln!(output, "Snapshot saved")?,
+                Some(Err(err)) => writeln!(output, "Failed to save the snapshot: {}", err)?,
+                None => writeln!(output, "Usage: save <path>")?,
+            },
+            "q" | "quit" => break,
+            "h" | "help" => writeln!(output, "{}", HELP)?,
+            _ => writeln!(output, "Unknown command '{}', try 'help'", command)?,
+        }
+
+        write!(output, "(zdb) ")?;
+        output.flush()?;
+    }
+
+    Ok(vm.exit)
+}
@@ -0,0 +1,152 @@
+use crate::parser::{Instruction, InstructionKind, ParserRet, ValueType};
+
+pub fn mnemonic(kind: &InstructionKind) -> &'static str {
+    match kind {
+        InstructionKind::Add => "add",
+        InstructionKind::Sub => "sub",
+        InstructionKind::Mul => "mul",
+        InstructionKind::Div => "div",
+        InstructionKind::Mod => "mod",
+        InstructionKind::Cmp => "cmp",
+        InstructionKind::Dup => "dup",
+        InstructionKind::Pop => "pop",
+        InstructionKind::Psh => "psh",
+        InstructionKind::Rot => "rot",
+        InstructionKind::Jmp => "jmp",
+        InstructionKind::Jnz => "jnz",
+        InstructionKind::Jzr => "jzr",
+        InstructionKind::Type => "typ",
+        InstructionKind::Ret => "ret",
+        InstructionKind::Run => "run",
+        InstructionKind::Sys => "sys",
+        InstructionKind::Len => "len",
+        InstructionKind::Ern => "ern",
+        InstructionKind::Lnw => "lnw",
+        InstructionKind::Lpu => "lpu",
+        InstructionKind::Lpo => "lpo",
+        InstructionKind::Lgt => "lgt",
+        InstructionKind::Lst => "lst",
+        InstructionKind::Lsl => "lsl",
+        InstructionKind::Mnw => "mnw",
+        InstructionKind::Mst => "mst",
+        InstructionKind::Mgt => "mgt",
+        InstructionKind::Mrm => "mrm",
+        InstructionKind::Mhs => "mhs",
+        InstructionKind::Mks => "mks",
+        InstructionKind::Lbl => "lbl",
+        InstructionKind::Fun => "fun",
+        InstructionKind::Fre => "dlc",
+        InstructionKind::Alc => "alc",
+        InstructionKind::Ast => "ast",
+        InstructionKind::Pnc => "pnc",
+        InstructionKind::Try => "try",
+        InstructionKind::End => "end",
+        InstructionKind::Thr => "thr",
+        InstructionKind::Agc => "agc",
+        InstructionKind::Arg => "arg",
+        InstructionKind::Env => "env",
+        InstructionKind::DebugSymbol => "dbg",
+    }
+}
+
+pub fn escape(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        match c {
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            '\0' => escaped.push_str("\\0"),
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            c if c.is_control() => escaped.push_str(&format!("\\u{{{:X}}}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Renders a value the way the lexer reads it back.
+pub fn literal(value: &ValueType) -> String {
+    match value {
+        ValueType::String(s) => format!("\"{}\"", escape(s)),
+        ValueType::Float(f) => format!("{:?}", f),
+        ValueType::DebugSymbol(ds) => format!("<{}:{}:{}>", ds.path, ds.line, ds.col),
+        _ => value.to_string(),
+    }
+}
+
+// Renders one instruction as canonical source, without indentation.
+pub fn render(instr: &Instruction) -> String {
+    match instr.kind {
+        InstructionKind::Lbl | InstructionKind::Fun => format!("{}:", instr.params[0]),
+        InstructionKind::DebugSymbol => literal(&instr.params[0]),
+        InstructionKind::Alc => format!("alc {}, {}", instr.params[0], literal(&instr.params[1])),
+        InstructionKind::Psh | InstructionKind::Pop | InstructionKind::Fre => format!("{} {}", mnemonic(&instr.kind), literal(&instr.params[0])),
+        InstructionKind::Ast | InstructionKind::Pnc => instr.params.iter().fold(mnemonic(&instr.kind).to_string(), |line, p| format!("{} {}", line, literal(p))),
+        _ => {
+            let mut line = mnemonic(&instr.kind).to_string();
+            for param in &instr.params {
+                line.push(' ');
+                line.push_str(&param.to_string());
+            }
+            line
+        },
+    }
+}
+
+fn target(program: &ParserRet, instr: &Instruction) -> Option<String> {
+    let name = instr.params.first()?.to_string();
+    let index = match instr.kind {
+        InstructionKind::Jmp | InstructionKind::Jnz | InstructionKind::Jzr | InstructionKind::Try => program.labels.get(&name),
+        InstructionKind::Run => program.funcs.get(&name),
+        _ => return None,
+    };
+
+    Some(index.map(|i| i.to_string()).unwrap_or("?".to_string()))
+}
+
+fn data(program: &ParserRet, out: &mut String) {
+    if !program.data.is_empty() {
+        out.push_str(".data\n");
+        for instr in &program.data {
+            out.push_str(&format!("    {}\n", render(instr)));
+        }
+    }
+}
+
+// Renders a parsed program back to source that parses to an equal program. Every instruction is
+// annotated with its index and, for jumps and calls, the index of its target.
+pub fn disasm(program: &ParserRet) -> String {
+    let mut out = String::new();
+
+    if let Some(module) = &program.module {
+        out.push_str(&format!("%module {}\n", module));
+    }
+    for export in &program.exports {
+        out.push_str(&format!("%export {}\n", export));
+    }
+
+    // `.data` ends at the next label or function, so instructions before the first one are written ahead of it.
+    let data_at = program.instrs.iter().position(|i| matches!(i.kind, InstructionKind::Lbl | InstructionKind::Fun)).unwrap_or(program.instrs.len());
+    for (i, instr) in program.instrs.iter().enumerate() {
+        if i == data_at {
+            data(program, &mut out);
+        }
+        let indent = match instr.kind {
+            InstructionKind::Lbl | InstructionKind::Fun => "",
+            _ => "    ",
+        };
+        let mut comment = format!("; {}", i);
+        if let Some(target) = target(program, instr) {
+            comment.push_str(&format!(" -> {}", target));
+        }
+
+        out.push_str(&format!("{:<39} {}\n", format!("{}{}", indent, render(instr)), comment));
+    }
+    if data_at == program.instrs.len() {
+        data(program, &mut out);
+    }
+
+    out
+}
@@ -1,367 +1,838 @@
-use std::collections::HashMap;
-use crate::parser::{ValueType, InstructionKind, ParserRet};
-use syscalls;
-use crate::Error;
-use crate::lexer::DebugSymbol;
-
-#[derive(Debug, PartialEq, Clone)]
-pub struct Buffer {
-    pub data: Vec<u8>,
-    pub size: usize,
-    pub ptr: usize,
-}
-
-fn ptr_to_vec(buf: Buffer) -> Vec<u8> {
-    let data = unsafe { std::slice::from_raw_parts(buf.ptr as *const u8, buf.size) };
-    data.to_vec()
-}
-
-fn trim_vec(buf: Vec<u8>) -> Vec<u8> {
-    let mut trimmed = buf.clone();
-    trimmed.retain(|&x| x != 0);
-    trimmed
-}
-
-pub fn evaluate(parsed: ParserRet) -> Result<(Vec<ValueType>, i32), Error> {
-    let instrs = parsed.instrs;
-    let labels = parsed.labels;
-    let funcs = parsed.funcs;
-
-    let mut vars: HashMap<String, ValueType> = HashMap::new();
-    let mut bufs: HashMap<String, Buffer> = HashMap::new();
-
-    let mut stack: Vec<ValueType> = Vec::new();
-    let mut ret_stack: Vec<usize> = Vec::new();
-
-    let mut current_debug_symbol: Option<DebugSymbol> = None;
-    let mut cur = *funcs.get("@entry").ok_or(Error::new("Entry function not found", 0, 0, &current_debug_symbol))?;
-
-    while cur < instrs.len() {
-        let instr = instrs.get(cur).ok_or(Error::new("Instruction not found", 0, 0, &current_debug_symbol))?;
-        match instr.kind {
-            InstructionKind::Psh => {
-                for param in &instr.params {
-                    if let ValueType::Variable(var_name) = param {
-                        let var = vars.get(var_name).ok_or(Error::new("Push: Variable not found", instr.line, instr.col, &current_debug_symbol))?;
-                        stack.push(var.clone());
-                } else {
-                        stack.push(param.clone());
-                    }
-                }
-            }
-            InstructionKind::Rot => {
-                let a = stack.pop().ok_or(Error::new("Rot: Stack underflow", instr.line, instr.col, &current_debug_symbol))?.clone();
-                let b = stack.pop().ok_or(Error::new("Rot: Stack underflow", instr.line, instr.col, &current_debug_symbol))?.clone();
-                stack.push(a);
-                stack.push(b);
-            },
-            InstructionKind::Add => {
-                let a = stack.pop().ok_or(Error::new("Add: Stack underflow", instr.line, instr.col, &current_debug_symbol))?.clone();
-                let b = stack.pop().ok_or(Error::new("Add: Stack underflow", instr.line, instr.col, &current_debug_symbol))?.clone();
-                let a_clone = a.clone();
-                let b_clone = b.clone();
-
-                match (a, b) {
-                    (ValueType::Integer(a), ValueType::Integer(b)) => {
-                        stack.push(ValueType::Integer(a + b));
-                    },
-                    (ValueType::Float(a), ValueType::Float(b)) => {
-                        stack.push(ValueType::Float(a + b));
-                    },
-                    (ValueType::String(a), ValueType::String(b)) => {
-                        stack.push(ValueType::String(format!("{}{}", b, a)));
-                    },
-
-                    _ => return Err(Error::new(format!("Invalid types for add {:?} {:?}", a_clone, b_clone), instr.line, instr.col, &current_debug_symbol)),
-                }
-            },
-            InstructionKind::Sub => {
-                let a = stack.pop().ok_or(Error::new("Sub: Stack underflow", instr.line, instr.col, &current_debug_symbol))?.clone();
-                let b = stack.pop().ok_or(Error::new("Sub: Stack underflow", instr.line, instr.col, &current_debug_symbol))?.clone();
-                let a_clone = a.clone();
-                let b_clone = b.clone();
-
-                match (a, b) {
-                    (ValueType::Integer(a), ValueType::Integer(b)) => {
-                        stack.push(ValueType::Integer(b - a));
-                    },
-                    (ValueType::Float(a), ValueType::Float(b)) => {
-                        stack.push(ValueType::Float(a - b));
-                    },
-                    (ValueType::String(a), ValueType::String(b)) => {
-                        stack.push(ValueType::String(b.replace(&a, "")));
-                    },
-
-                    _ => return Err(Error::new(format!("Invalid types for sub {:?} {:?}", a_clone, b_clone), instr.line, instr.col, &current_debug_symbol)),
-                }
-            },
-            InstructionKind::Mul => {
-                let a = stack.pop().ok_or(Error::new("Mul: Stack underflow", instr.line, instr.col, &current_debug_symbol))?.clone();
-                let b = stack.pop().ok_or(Error::new("Mul: Stack underflow", instr.line, instr.col, &current_debug_symbol))?.clone();
-                let a_clone = a.clone();
-                let b_clone = b.clone();
-
-                match (a, b) {
-                    (ValueType::Integer(a), ValueType::Integer(b)) => {
-                        stack.push(ValueType::Integer(a * b));
-                    },
-                    (ValueType::Float(a), ValueType::Float(b)) => {
-                        stack.push(ValueType::Float(a * b));
-                    },
-
-                    (ValueType::String(a), ValueType::Integer(b)) | (ValueType::Integer(b), ValueType::String(a)) => {
-                        stack.push(ValueType::String(a.repeat(b as usize)));
-                    },
-                    _ => return Err(Error::new(format!("Invalid types for mul {:?} {:?}", a_clone, b_clone), instr.line, instr.col, &current_debug_symbol)),
-                }
-            },
-            InstructionKind::Div => {
-                let a = stack.pop().ok_or(Error::new("Div: Stack underflow", instr.line, instr.col, &current_debug_symbol))?.clone();
-                let b = stack.pop().ok_or(Error::new("Div: Stack underflow", instr.line, instr.col, &current_debug_symbol))?.clone();
-                let a_clone = a.clone();
-                let b_clone = b.clone();
-
-                match (a, b) {
-                    (ValueType::Integer(a), ValueType::Integer(b)) => {
-                        stack.push(ValueType::Integer(b / a));
-                    },
-                    (ValueType::Float(a), ValueType::Float(b)) => {
-                        stack.push(ValueType::Float(a / b));
-                    },
-
-                    _ => return Err(Error::new(format!("Invalid types for div {:?} {:?}", a_clone, b_clone), instr.line, instr.col, &current_debug_symbol)),
-                }
-            },
-            InstructionKind::Mod => {
-                let a = stack.pop().ok_or(Error::new("Mod: Stack underflow", instr.line, instr.col, &current_debug_symbol))?.clone();
-                let b = stack.pop().ok_or(Error::new("Mod: Stack underflow", instr.line, instr.col, &current_debug_symbol))?.clone();
-                let a_clone = a.clone();
-                let b_clone = b.clone();
-
-                match (a, b) {
-                    (ValueType::Integer(a), ValueType::Integer(b)) => {
-                        stack.push(ValueType::Integer(b % a));
-                    },
-                    (ValueType::Float(a), ValueType::Float(b)) => {
-                        stack.push(ValueType::Float(a % b));
-                    },
-
-                    _ => return Err(Error::new(format!("Invalid types for mod {:?} {:?}", a_clone, b_clone), instr.line, instr.col, &current_debug_symbol)),
-                }
-            },
-            InstructionKind::Cmp => {
-                let a = stack.pop().ok_or(Error::new("Equal: Stack underflow", instr.line, instr.col, &current_debug_symbol))?.clone();
-                let b = stack.pop().ok_or(Error::new("Equal: Stack underflow", instr.line, instr.col, &current_debug_symbol))?.clone();
-                let a_clone = a.clone();
-                let b_clone = b.clone();
-
-                match (a, b) {
-                    (ValueType::Integer(a), ValueType::Integer(b)) => {
-                        stack.push(ValueType::Boolean(a == b));
-                    },
-                    (ValueType::Float(a), ValueType::Float(b)) => {
-                        stack.push(ValueType::Boolean(a == b));
-                    },
-                    (ValueType::String(a), ValueType::String(b)) => {
-                        stack.push(ValueType::Boolean(a == b));
-                    },
-                    (ValueType::Boolean(a), ValueType::Boolean(b)) => {
-                        stack.push(ValueType::Boolean(a == b));
-                    },
-                    _ => return Err(Error::new(format!("Invalid types for equal {:?} {:?}", a_clone, b_clone), instr.line, instr.col, &current_debug_symbol)),
-                }
-            }
-            InstructionKind::Pop => {
-                let a = stack.pop().ok_or(Error::new("Pop: Stack underflow", instr.line, instr.col, &current_debug_symbol))?;
-                let var_name = instr.params[0].clone().to_string();
-                if var_name != "$_" && var_name != "$" {
-                    vars.insert(var_name, a).map(|old| old);
-                }
-            },
-            InstructionKind::Dup => {
-                let a = stack.last().ok_or(Error::new("Dup: Stack underflow", instr.line, instr.col, &current_debug_symbol))?.clone();
-                stack.push(a);
-            },
-            InstructionKind::Jmp => {
-                let label = instr.params[0].clone();
-                let i = labels.get(&label.to_string()).ok_or(Error::new("Jump: Label not found", instr.line, instr.col, &current_debug_symbol))?;
-                cur = *i;
-            }
-            InstructionKind::Jnz => {
-                let label = instr.params[0].clone();
-                let i = labels.get(&label.to_string()).ok_or(Error::new("Jnz: Label not found", instr.line, instr.col, &current_debug_symbol))? - 1;
-                let a = stack.pop().ok_or(Error::new("Jnz: Stack underflow", instr.line, instr.col, &current_debug_symbol))?.clone();
-                match a {
-                    ValueType::Integer(n) => if n != 0 { cur = i; },
-                    ValueType::Float(n) => if n != 0.0 { cur = i; },
-                    ValueType::String(n) => if n != "" { cur = i; },
-                    ValueType::Boolean(n) => if n { cur = i; },
-                    _ => return Err(Error::new("Jnz: Invalid type".to_string(), instr.line, instr.col, &current_debug_symbol)),
-                }
-            },InstructionKind::Jzr => {
-                let label = instr.params[0].clone();
-                let i = labels.get(&label.to_string()).ok_or(Error::new("Jzr: Label not found", instr.line, instr.col, &current_debug_symbol))? - 1;
-                let a = stack.pop().ok_or(Error::new("Jzr: Stack underflow", instr.line, instr.col, &current_debug_symbol))?.clone();
-                match a {
-                    ValueType::Integer(n) => if n == 0 { cur = i; },
-                    ValueType::Float(n) => if n == 0.0 { cur = i; },
-                    ValueType::String(n) => if n == "" { cur = i; },
-                    ValueType::Boolean(n) => if !n { cur = i; },
-                    _ => return Err(Error::new("Jzr: Invalid type".to_string(), instr.line, instr.col, &current_debug_symbol)),
-                }
-            },
-            InstructionKind::Type => {
-                let label = match instr.params[0].clone() {
-                    ValueType::String(s) => s,
-                    _ => return Err(Error::new("Type: Invalid type".to_string(), instr.line, instr.col, &current_debug_symbol)),
-                };
-                let a = match stack.pop().ok_or(Error::new("Type: Stack underflow", instr.line, instr.col, &current_debug_symbol))?.clone() {
-                    ValueType::String(s) => s,
-                    ValueType::Integer(i) => i.to_string(),
-                    ValueType::Float(f) => f.to_string(),
-                    ValueType::Boolean(b) => b.to_string(),
-                    ValueType::Buffer(b) => {
-                        let buf = bufs.get(&b).ok_or(Error::new("Type: Buffer not found", instr.line, instr.col, &current_debug_symbol))?.clone();
-                        let vec = ptr_to_vec(buf);
-                        let trimmed_vec = trim_vec(vec);
-                        String::from_utf8(trimmed_vec).unwrap()
-                    },
-                    _ => return Err(Error::new("Type: Invalid type".to_string(), instr.line, instr.col, &current_debug_symbol)),
-                };
-
-                let res = match label {
-                    s if s == "int" => {
-                        match a.parse::<i32>() {
-                            Ok(i) => ValueType::Integer(i),
-                            Err(_) => match a.parse::<bool>() {
-                                Ok(b) => ValueType::Integer(b as i32),
-                                Err(_) => return Err(Error::new("Type: Invalid int or bool".to_string(), instr.line, instr.col, &current_debug_symbol)),
-                            },
-                        }
-                    },
-                    s if s == "float" => {
-                        match a.parse::<f32>() {
-                            Ok(f) => ValueType::Float(f),
-                            Err(_) => return Err(Error::new("Type: Invalid float".to_string(), instr.line, instr.col, &current_debug_symbol)),
-                        }
-                    },
-                    s if s == "str" => ValueType::String(a),
-                    s if s == "bool" => {
-                        match a.parse::<bool>() {
-                            Ok(b) => ValueType::Boolean(b),
-                            Err(_) => return Err(Error::new("Type: Invalid bool".to_string(), instr.line, instr.col, &current_debug_symbol)),
-                        }
-                    },
-                    _ => return Err(Error::new("Type: Invalid type".to_string(), instr.line, instr.col, &current_debug_symbol)),
-                };
-
-                stack.push(res);
-            },
-            InstructionKind::Ret => {
-                if let Some(i) = ret_stack.pop() {
-                    cur = i;
-                } else {
-                    let a = stack.pop().ok_or(Error::new("Ret: Stack underflow", instr.line, instr.col, &current_debug_symbol))?;
-                    return Ok((stack, a.to_int().map_err(|e| Error::new(e, instr.line, instr.col, &current_debug_symbol))?));
-                }
-            },
-            InstructionKind::Run => {
-                let func = instr.params[0].clone();
-                let i = funcs.get(&func.to_string()).ok_or(Error::new("Run: Function not found", instr.line, instr.col, &current_debug_symbol))?;
-                ret_stack.push(cur);
-                cur = *i;
-
-            },
-            InstructionKind::Sys => {
-                let syscall_number = stack.pop().ok_or(Error::new("Sys: Stack underflow", instr.line, instr.col, &current_debug_symbol))?.clone();
-                let mut args = Vec::new();
-
-                for _ in 0..6 {
-                    if let Some(arg) = stack.pop() {
-                        args.push(arg);
-                    } else {
-                        args.push(ValueType::Integer(0)); // Default to 0 if not enough arguments
-                    }
-                }
-
-                let result = match syscall_number {
-                    ValueType::Integer(num) => {
-                        let syscall_args: Vec<usize> = args.iter().map(|arg| match arg {
-                            ValueType::Integer(i) => *i as usize,
-                            ValueType::Float(f) => *f as usize,
-                            ValueType::Boolean(b) => *b as usize,
-                            ValueType::String(s) => {
-                                s.as_ptr() as usize
-                            },
-                            ValueType::Buffer(b) => {
-                                let buf = bufs.get(b).ok_or(Error::new("Sys: Buffer not found", instr.line, instr.col, &current_debug_symbol)).unwrap().clone();
-                                buf.ptr
-                            },
-                            ValueType::Variable(v) => {
-                                v.len()
-                            }
-                            ValueType::DebugSymbol(_) => {
-                                Err(Error::new("Sys: Debug symbol not allowed".to_string(), instr.line, instr.col, &current_debug_symbol)).unwrap()
-                            }
-                        }).collect();
-
-                        let syscall_args = syscalls::SyscallArgs::new(syscall_args[0], syscall_args[1], syscall_args[2], syscall_args[3], syscall_args[4], syscall_args[5]);
-                        let result = unsafe { syscalls::syscall(syscalls::Sysno::from(num as u32), &syscall_args) };
-                        result
-                    },
-                    _ => return Err(Error::new("Sys: Invalid syscall number type".to_string(), instr.line, instr.col, &current_debug_symbol)),
-                };
-
-                stack.push(ValueType::Integer(result.unwrap() as i32));
-            },
-            InstructionKind::Len => {
-                let a = stack.last().ok_or(Error::new("Len: Stack underflow", instr.line, instr.col, &current_debug_symbol))?.clone();
-                let len = match a {
-                    ValueType::String(s) => s.len(),
-                    ValueType::Buffer(b) => {
-                        let buf = bufs.get(&b).ok_or(Error::new("Len: Buffer not found", instr.line, instr.col, &current_debug_symbol))?.clone();
-                        buf.size
-                    },
-                    _ => return Err(Error::new("Len: Invalid type".to_string(), instr.line, instr.col, &current_debug_symbol)),
-                };
-                stack.push(ValueType::Integer(len as i32));
-            },
-            InstructionKind::Fre => {
-                let a = instr.params[0].clone();
-                match a {
-                    ValueType::Buffer(b) => {
-                        bufs.remove(&b);
-                    },
-                    ValueType::Variable(v) => {
-                        vars.remove(&v);
-                    },
-                    _ => return Err(Error::new("Fre: Invalid type".to_string(), instr.line, instr.col, &current_debug_symbol)),
-                };
-            }
-            InstructionKind::Lbl => {}
-            InstructionKind::Fun => {}
-            InstructionKind::Alc => {
-                let name = instr.params[0].clone().to_string();
-                let size = instr.params[1].to_int().map_err(|e| Error::new(e, instr.line, instr.col, &current_debug_symbol))? as usize;
-                let mut data = vec![0u8; size];
-                let ptr = data.as_mut_ptr() as usize;
-
-                let buffer = Buffer {
-                    data,
-                    size,
-                    ptr,
-                };
-
-                bufs.insert(name, buffer);
-            },
-            InstructionKind::DebugSymbol => {
-                let debug_symbol = instr.params[0].as_debug_symbol().unwrap();
-                current_debug_symbol = Some(debug_symbol);
-            }
-        }
-
-        cur += 1;
-    }
-
-    Ok((stack, 0))
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::rc::Rc;
+use std::time::Instant;
+use crate::parser::{MapKey, ValueType, Instruction, InstructionKind, ParserRet};
+use crate::{Error, ErrorKind};
+use crate::lexer::DebugSymbol;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Buffer {
+    pub data: Vec<u8>,
+    pub size: usize,
+    pub ptr: usize,
+}
+
+fn ptr_to_vec(buf: Buffer) -> Vec<u8> {
+    let data = unsafe { std::slice::from_raw_parts(buf.ptr as *const u8, buf.size) };
+    data.to_vec()
+}
+
+fn trim_vec(buf: Vec<u8>) -> Vec<u8> {
+    let mut trimmed = buf.clone();
+    trimmed.retain(|&x| x != 0);
+    trimmed
+}
+
+// The list on top of the stack, which list instructions leave in place.
+fn top_list(stack: &[ValueType], name: &str, instr: &Instruction, debug_symbol: &Option<DebugSymbol>) -> Result<Rc<RefCell<Vec<ValueType>>>, Error> {
+    match stack.last() {
+        Some(ValueType::List(list)) => Ok(list.clone()),
+        Some(_) => Err(Error::new(format!("{}: Expected a list", name), instr.line, instr.col, debug_symbol).in_file(&instr.file)),
+        None => Err(Error::new(format!("{}: Stack underflow", name), instr.line, instr.col, debug_symbol).in_file(&instr.file)),
+    }
+}
+
+// How deeply lists and maps nest in `value`, counting `value` itself, or None if `container` is among
+// them or one of them contains itself. Walks each list or map once and without recursing, since a
+// restored snapshot may nest deeper than the host stack allows.
+pub fn nesting(value: &ValueType, container: *const ()) -> Option<usize> {
+    fn items(value: &ValueType) -> Option<(*const (), Vec<ValueType>)> {
+        match value {
+            ValueType::List(l) => Some((Rc::as_ptr(l) as *const (), l.borrow().clone())),
+            ValueType::Map(m) => Some((Rc::as_ptr(m) as *const (), m.borrow().values().cloned().collect())),
+            _ => None,
+        }
+    }
+
+    let Some((ptr, rest)) = items(value) else { return Some(0) };
+    if ptr == container {
+        return None;
+    }
+    // Each entry is a list or map being walked, the items left to visit and the deepest nesting below it so far.
+    let mut path = vec![(ptr, rest, 0)];
+    let mut on_path = HashSet::from([ptr]);
+    let mut depths: HashMap<*const (), usize> = HashMap::new();
+
+    loop {
+        let top = path.len() - 1;
+        match path[top].1.pop().as_ref().and_then(items) {
+            Some((ptr, _)) if ptr == container || on_path.contains(&ptr) => return None,
+            Some((ptr, _)) if depths.contains_key(&ptr) => path[top].2 = path[top].2.max(depths[&ptr]),
+            Some((ptr, rest)) => {
+                on_path.insert(ptr);
+                path.push((ptr, rest, 0));
+            },
+            None if !path[top].1.is_empty() => {},
+            None => {
+                let (ptr, _, below) = path.pop().unwrap();
+                on_path.remove(&ptr);
+                depths.insert(ptr, below + 1);
+                match path.last_mut() {
+                    Some(parent) => parent.2 = parent.2.max(below + 1),
+                    None => return Some(below + 1),
+                }
+            },
+        }
+    }
+}
+
+// The map on top of the stack, which map instructions leave in place.
+fn top_map(stack: &[ValueType], name: &str, instr: &Instruction, debug_symbol: &Option<DebugSymbol>) -> Result<Rc<RefCell<BTreeMap<MapKey, ValueType>>>, Error> {
+    match stack.last() {
+        Some(ValueType::Map(map)) => Ok(map.clone()),
+        Some(_) => Err(Error::new(format!("{}: Expected a map", name), instr.line, instr.col, debug_symbol).in_file(&instr.file)),
+        None => Err(Error::new(format!("{}: Stack underflow", name), instr.line, instr.col, debug_symbol).in_file(&instr.file)),
+    }
+}
+
+fn pop_key(stack: &mut Vec<ValueType>, name: &str, instr: &Instruction, debug_symbol: &Option<DebugSymbol>) -> Result<MapKey, Error> {
+    let error = |message: String| Error::new(message, instr.line, instr.col, debug_symbol).in_file(&instr.file);
+    let a = stack.pop().ok_or(error(format!("{}: Stack underflow", name)))?;
+    MapKey::new(&a).map_err(|e| error(format!("{}: {}", name, e)))
+}
+
+// Pops a list index, which can't be negative. Checking the upper bound is left to the caller.
+fn pop_index(stack: &mut Vec<ValueType>, name: &str, instr: &Instruction, debug_symbol: &Option<DebugSymbol>) -> Result<usize, Error> {
+    let error = |message: String| Error::new(message, instr.line, instr.col, debug_symbol).in_file(&instr.file);
+    let a = stack.pop().ok_or(error(format!("{}: Stack underflow", name)))?;
+    let index = a.to_int().map_err(|e| error(format!("{}: {}", name, e)))?;
+    usize::try_from(index).map_err(|_| error(format!("{}: Index {} out of range", name, index)))
+}
+
+// Allocates the buffer of an `alc`, refusing to go past `available` bytes.
+fn allocate(instr: &Instruction, debug_symbol: &Option<DebugSymbol>, available: usize) -> Result<Buffer, Error> {
+    let error = |message: String| Error::new(message, instr.line, instr.col, debug_symbol).in_file(&instr.file);
+    let size = match &instr.params[1] {
+        ValueType::String(s) => s.len(),
+        size => usize::try_from(size.to_int().map_err(error)?).map_err(|_| error("Alc: Negative buffer size".to_string()))?,
+    };
+    if size > available {
+        return Err(error(format!("Alc: Buffer limit exceeded, only {} bytes left", available)).with_kind(ErrorKind::LimitExceeded));
+    }
+
+    let mut data = match &instr.params[1] {
+        ValueType::String(s) => s.as_bytes().to_vec(),
+        _ => vec![0u8; size],
+    };
+    let size = data.len();
+    let ptr = data.as_mut_ptr() as usize;
+
+    Ok(Buffer {
+        data,
+        size,
+        ptr,
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    pub stack: usize,
+    pub ret_stack: usize,
+    pub vars: usize,
+    pub buffer_bytes: usize,
+    pub string_len: usize,
+    // Items in a single list or map.
+    pub items: usize,
+    // Levels of lists and maps nested in each other.
+    pub depth: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            stack: 1 << 20,
+            ret_stack: 1 << 16,
+            vars: 1 << 16,
+            buffer_bytes: 1 << 30,
+            string_len: 1 << 24,
+            items: 1 << 24,
+            depth: 1 << 10,
+        }
+    }
+}
+
+impl Limits {
+    fn check_string(&self, instr: &Instruction, len: usize, debug_symbol: &Option<DebugSymbol>) -> Result<(), Error> {
+        if len > self.string_len {
+            let message = format!("String length limit of {} exceeded", self.string_len);
+            return Err(Error::new(message, instr.line, instr.col, debug_symbol).in_file(&instr.file).with_kind(ErrorKind::LimitExceeded));
+        }
+        Ok(())
+    }
+
+    fn check_items(&self, name: &str, instr: &Instruction, len: usize, debug_symbol: &Option<DebugSymbol>) -> Result<(), Error> {
+        if len > self.items {
+            let message = format!("{}: Item limit of {} exceeded", name, self.items);
+            return Err(Error::new(message, instr.line, instr.col, debug_symbol).in_file(&instr.file).with_kind(ErrorKind::LimitExceeded));
+        }
+        Ok(())
+    }
+
+    fn check_depth(&self, name: &str, instr: &Instruction, depth: usize, debug_symbol: &Option<DebugSymbol>) -> Result<(), Error> {
+        if depth > self.depth {
+            let message = format!("{}: Depth limit of {} exceeded", name, self.depth);
+            return Err(Error::new(message, instr.line, instr.col, debug_symbol).in_file(&instr.file).with_kind(ErrorKind::LimitExceeded));
+        }
+        Ok(())
+    }
+}
+
+// An active `try` region: the index of its handler label and the stack and return stack depths to
+// unwind to.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Handler {
+    pub label: usize,
+    pub stack: usize,
+    pub calls: usize,
+}
+
+pub struct Vm {
+    pub program: ParserRet,
+    pub vars: HashMap<String, ValueType>,
+    pub bufs: HashMap<String, Buffer>,
+    pub stack: Vec<ValueType>,
+    pub ret_stack: Vec<usize>,
+    pub current_debug_symbol: Option<DebugSymbol>,
+    pub cur: usize,
+    pub exit: Option<i32>,
+    // Instructions left to execute and the time to stop at. Running out leaves the VM where it was,
+    // so execution can resume after topping them up.
+    pub fuel: Option<u64>,
+    pub deadline: Option<Instant>,
+    pub limits: Limits,
+    // Arguments and environment visible to `agc`, `arg` and `env`. Both start out empty.
+    pub args: Vec<String>,
+    pub env: HashMap<String, String>,
+    pub handlers: Vec<Handler>,
+}
+
+fn function_at(program: &ParserRet, index: usize) -> Option<&str> {
+    program.instrs[..=index.min(program.instrs.len().saturating_sub(1))].iter().rev()
+        .find(|i| i.kind == InstructionKind::Fun)
+        .and_then(|i| match &i.params[0] {
+            ValueType::String(name) => Some(name.as_str()),
+            _ => None,
+        })
+}
+
+fn backtrace(program: &ParserRet, cur: usize, ret_stack: &[usize]) -> Vec<String> {
+    std::iter::once(&cur).chain(ret_stack.iter().rev()).filter_map(|&index| {
+        let instr = program.instrs.get(index)?;
+        let location = match &instr.file {
+            Some(file) => format!("{}:{}:{}", file, instr.line, instr.col),
+            None => format!("{}:{}", instr.line, instr.col),
+        };
+        Some(format!("{} ({})", function_at(program, index).unwrap_or("?"), location))
+    }).collect()
+}
+
+// Drops everything pushed and called since `handler` was set up and continues at its label with
+// `value` on the stack.
+fn unwind(handler: Handler, stack: &mut Vec<ValueType>, ret_stack: &mut Vec<usize>, cur: &mut usize, value: ValueType) {
+    stack.truncate(handler.stack);
+    ret_stack.truncate(handler.calls);
+    stack.push(value);
+    *cur = handler.label;
+}
+
+impl Vm {
+    pub fn new(program: ParserRet) -> Result<Self, Error> {
+        Self::with_entry(program, "@entry")
+    }
+
+    pub fn with_entry(program: ParserRet, entry: &str) -> Result<Self, Error> {
+        Self::with_limits(program, entry, Limits::default())
+    }
+
+    // Allocates the `.data` section within `limits` and positions the VM at the function `entry`.
+    pub fn with_limits(program: ParserRet, entry: &str, limits: Limits) -> Result<Self, Error> {
+        let mut bufs: HashMap<String, Buffer> = HashMap::new();
+        let mut available = limits.buffer_bytes;
+        for instr in &program.data {
+            let buffer = allocate(instr, &None, available)?;
+            available -= buffer.size;
+            bufs.insert(instr.params[0].to_string(), buffer);
+        }
+
+        let cur = *program.funcs.get(entry).ok_or(Error::new(format!("Entry function {} not found", entry), 0, 0, &None))?;
+
+        Ok(Self {
+            program,
+            vars: HashMap::new(),
+            bufs,
+            stack: Vec::new(),
+            ret_stack: Vec::new(),
+            current_debug_symbol: None,
+            cur,
+            exit: None,
+            fuel: None,
+            deadline: None,
+            limits,
+            args: Vec::new(),
+            env: HashMap::new(),
+            handlers: Vec::new(),
+        })
+    }
+
+    // Name of the function whose body contains the instruction at `index`.
+    pub fn function_at(&self, index: usize) -> Option<&str> {
+        function_at(&self.program, index)
+    }
+
+    // The current function followed by every caller, each with the location of the instruction it
+    // is at.
+    pub fn backtrace(&self) -> Vec<String> {
+        backtrace(&self.program, self.cur, &self.ret_stack)
+    }
+
+    // Executes the instruction at `cur`, returning the exit code once the program has finished.
+    // Runtime errors inside a `try` region are caught, with the handler receiving the error message.
+    pub fn step(&mut self) -> Result<Option<i32>, Error> {
+        match self.execute() {
+            Err(err) if err.kind == ErrorKind::Other && !self.handlers.is_empty() => {
+                let handler = self.handlers.pop().unwrap();
+                unwind(handler, &mut self.stack, &mut self.ret_stack, &mut self.cur, ValueType::String(err.message));
+                self.cur += 1;
+                Ok(None)
+            },
+            result => result,
+        }
+    }
+
+    fn execute(&mut self) -> Result<Option<i32>, Error> {
+        if let Some(code) = self.exit {
+            return Ok(Some(code));
+        }
+        if self.cur >= self.program.instrs.len() {
+            self.exit = Some(0);
+            return Ok(Some(0));
+        }
+
+        let Vm { program, vars, bufs, stack, ret_stack, current_debug_symbol, cur, exit, fuel, deadline, limits, args, env, handlers } = self;
+        let labels = &program.labels;
+        let funcs = &program.funcs;
+        let instr = &program.instrs[*cur];
+
+        if *fuel == Some(0) {
+            return Err(Error::new("Out of fuel", instr.line, instr.col, current_debug_symbol).in_file(&instr.file).with_kind(ErrorKind::OutOfFuel));
+        }
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            return Err(Error::new("Deadline exceeded", instr.line, instr.col, current_debug_symbol).in_file(&instr.file).with_kind(ErrorKind::Timeout));
+        }
+        if let Some(fuel) = fuel {
+            *fuel -= 1;
+        }
+
+        match instr.kind {
+            InstructionKind::Psh => {
+                for param in &instr.params {
+                    if let ValueType::Variable(var_name) = param {
+                        let var = vars.get(var_name).ok_or(Error::new("Push: Variable not found", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                        stack.push(var.clone());
+                    } else {
+                        stack.push(param.clone());
+                    }
+                }
+            }
+            InstructionKind::Rot => {
+                let a = stack.pop().ok_or(Error::new("Rot: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?.clone();
+                let b = stack.pop().ok_or(Error::new("Rot: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?.clone();
+                stack.push(a);
+                stack.push(b);
+            },
+            InstructionKind::Add => {
+                let a = stack.pop().ok_or(Error::new("Add: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?.clone();
+                let b = stack.pop().ok_or(Error::new("Add: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?.clone();
+                let a_clone = a.clone();
+                let b_clone = b.clone();
+
+                match (a, b) {
+                    (ValueType::Integer(a), ValueType::Integer(b)) => {
+                        stack.push(ValueType::Integer(a.checked_add(b).ok_or(Error::new("Add: Integer overflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?));
+                    },
+                    (ValueType::Float(a), ValueType::Float(b)) => {
+                        stack.push(ValueType::Float(a + b));
+                    },
+                    (ValueType::String(a), ValueType::String(b)) => {
+                        limits.check_string(instr, a.len() + b.len(), current_debug_symbol)?;
+                        stack.push(ValueType::String(format!("{}{}", b, a)));
+                    },
+
+                    _ => return Err(Error::new(format!("Invalid types for add {:?} {:?}", a_clone, b_clone), instr.line, instr.col, current_debug_symbol).in_file(&instr.file)),
+                }
+            },
+            InstructionKind::Sub => {
+                let a = stack.pop().ok_or(Error::new("Sub: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?.clone();
+                let b = stack.pop().ok_or(Error::new("Sub: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?.clone();
+                let a_clone = a.clone();
+                let b_clone = b.clone();
+
+                match (a, b) {
+                    (ValueType::Integer(a), ValueType::Integer(b)) => {
+                        stack.push(ValueType::Integer(b.checked_sub(a).ok_or(Error::new("Sub: Integer overflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?));
+                    },
+                    (ValueType::Float(a), ValueType::Float(b)) => {
+                        stack.push(ValueType::Float(a - b));
+                    },
+                    (ValueType::String(a), ValueType::String(b)) => {
+                        stack.push(ValueType::String(b.replace(&a, "")));
+                    },
+
+                    _ => return Err(Error::new(format!("Invalid types for sub {:?} {:?}", a_clone, b_clone), instr.line, instr.col, current_debug_symbol).in_file(&instr.file)),
+                }
+            },
+            InstructionKind::Mul => {
+                let a = stack.pop().ok_or(Error::new("Mul: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?.clone();
+                let b = stack.pop().ok_or(Error::new("Mul: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?.clone();
+                let a_clone = a.clone();
+                let b_clone = b.clone();
+
+                match (a, b) {
+                    (ValueType::Integer(a), ValueType::Integer(b)) => {
+                        stack.push(ValueType::Integer(a.checked_mul(b).ok_or(Error::new("Mul: Integer overflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?));
+                    },
+                    (ValueType::Float(a), ValueType::Float(b)) => {
+                        stack.push(ValueType::Float(a * b));
+                    },
+
+                    (ValueType::String(a), ValueType::Integer(b)) | (ValueType::Integer(b), ValueType::String(a)) => {
+                        let times = usize::try_from(b).map_err(|_| Error::new("Mul: Negative repeat count", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                        limits.check_string(instr, a.len().saturating_mul(times), current_debug_symbol)?;
+                        stack.push(ValueType::String(a.repeat(times)));
+                    },
+                    _ => return Err(Error::new(format!("Invalid types for mul {:?} {:?}", a_clone, b_clone), instr.line, instr.col, current_debug_symbol).in_file(&instr.file)),
+                }
+            },
+            InstructionKind::Div => {
+                let a = stack.pop().ok_or(Error::new("Div: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?.clone();
+                let b = stack.pop().ok_or(Error::new("Div: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?.clone();
+                let a_clone = a.clone();
+                let b_clone = b.clone();
+
+                match (a, b) {
+                    (ValueType::Integer(a), ValueType::Integer(b)) => {
+                        if a == 0 {
+                            return Err(Error::new("Div: Division by zero", instr.line, instr.col, current_debug_symbol).in_file(&instr.file));
+                        }
+                        stack.push(ValueType::Integer(b.checked_div(a).ok_or(Error::new("Div: Integer overflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?));
+                    },
+                    (ValueType::Float(a), ValueType::Float(b)) => {
+                        stack.push(ValueType::Float(a / b));
+                    },
+
+                    _ => return Err(Error::new(format!("Invalid types for div {:?} {:?}", a_clone, b_clone), instr.line, instr.col, current_debug_symbol).in_file(&instr.file)),
+                }
+            },
+            InstructionKind::Mod => {
+                let a = stack.pop().ok_or(Error::new("Mod: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?.clone();
+                let b = stack.pop().ok_or(Error::new("Mod: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?.clone();
+                let a_clone = a.clone();
+                let b_clone = b.clone();
+
+                match (a, b) {
+                    (ValueType::Integer(a), ValueType::Integer(b)) => {
+                        if a == 0 {
+                            return Err(Error::new("Mod: Division by zero", instr.line, instr.col, current_debug_symbol).in_file(&instr.file));
+                        }
+                        stack.push(ValueType::Integer(b.checked_rem(a).ok_or(Error::new("Mod: Integer overflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?));
+                    },
+                    (ValueType::Float(a), ValueType::Float(b)) => {
+                        stack.push(ValueType::Float(a % b));
+                    },
+
+                    _ => return Err(Error::new(format!("Invalid types for mod {:?} {:?}", a_clone, b_clone), instr.line, instr.col, current_debug_symbol).in_file(&instr.file)),
+                }
+            },
+            InstructionKind::Cmp => {
+                let a = stack.pop().ok_or(Error::new("Equal: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?.clone();
+                let b = stack.pop().ok_or(Error::new("Equal: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?.clone();
+                let a_clone = a.clone();
+                let b_clone = b.clone();
+
+                match (a, b) {
+                    (ValueType::Integer(a), ValueType::Integer(b)) => {
+                        stack.push(ValueType::Boolean(a == b));
+                    },
+                    (ValueType::Float(a), ValueType::Float(b)) => {
+                        stack.push(ValueType::Boolean(a == b));
+                    },
+                    (ValueType::String(a), ValueType::String(b)) => {
+                        stack.push(ValueType::Boolean(a == b));
+                    },
+                    (ValueType::Boolean(a), ValueType::Boolean(b)) => {
+                        stack.push(ValueType::Boolean(a == b));
+                    },
+                    (ValueType::List(a), ValueType::List(b)) => {
+                        stack.push(ValueType::Boolean(a == b));
+                    },
+                    (ValueType::Map(a), ValueType::Map(b)) => {
+                        stack.push(ValueType::Boolean(a == b));
+                    },
+                    _ => return Err(Error::new(format!("Invalid types for equal {:?} {:?}", a_clone, b_clone), instr.line, instr.col, current_debug_symbol).in_file(&instr.file)),
+                }
+            }
+            InstructionKind::Pop => {
+                let a = stack.pop().ok_or(Error::new("Pop: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                let var_name = instr.params[0].clone().to_string();
+                if var_name != "$_" && var_name != "$" {
+                    if !vars.contains_key(&var_name) && vars.len() >= limits.vars {
+                        let message = format!("Pop: Variable limit of {} exceeded", limits.vars);
+                        return Err(Error::new(message, instr.line, instr.col, current_debug_symbol).in_file(&instr.file).with_kind(ErrorKind::LimitExceeded));
+                    }
+                    vars.insert(var_name, a);
+                }
+            },
+            InstructionKind::Dup => {
+                let a = stack.last().ok_or(Error::new("Dup: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?.clone();
+                stack.push(a);
+            },
+            InstructionKind::Jmp => {
+                let label = instr.params[0].clone();
+                let i = labels.get(&label.to_string()).ok_or(Error::new("Jump: Label not found", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                *cur = *i;
+            }
+            InstructionKind::Jnz => {
+                let label = instr.params[0].clone();
+                let i = labels.get(&label.to_string()).ok_or(Error::new("Jnz: Label not found", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))? - 1;
+                let a = stack.pop().ok_or(Error::new("Jnz: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?.clone();
+                let truthy = a.to_bool().map_err(|e| Error::new(format!("Jnz: {}", e), instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                if truthy {
+                    *cur = i;
+                }
+            },InstructionKind::Jzr => {
+                let label = instr.params[0].clone();
+                let i = labels.get(&label.to_string()).ok_or(Error::new("Jzr: Label not found", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))? - 1;
+                let a = stack.pop().ok_or(Error::new("Jzr: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?.clone();
+                let truthy = a.to_bool().map_err(|e| Error::new(format!("Jzr: {}", e), instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                if !truthy {
+                    *cur = i;
+                }
+            },
+            InstructionKind::Type => {
+                let label = match instr.params[0].clone() {
+                    ValueType::String(s) => s,
+                    _ => return Err(Error::new("Type: Invalid type".to_string(), instr.line, instr.col, current_debug_symbol).in_file(&instr.file)),
+                };
+                let a = match stack.pop().ok_or(Error::new("Type: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?.clone() {
+                    ValueType::String(s) => s,
+                    ValueType::Integer(i) => i.to_string(),
+                    ValueType::Float(f) => f.to_string(),
+                    ValueType::Boolean(b) => b.to_string(),
+                    ValueType::Buffer(b) => {
+                        let buf = bufs.get(&b).ok_or(Error::new("Type: Buffer not found", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?.clone();
+                        let vec = ptr_to_vec(buf);
+                        let trimmed_vec = trim_vec(vec);
+                        String::from_utf8(trimmed_vec).unwrap()
+                    },
+                    value @ (ValueType::List(_) | ValueType::Map(_)) => value.to_string(),
+                    _ => return Err(Error::new("Type: Invalid type".to_string(), instr.line, instr.col, current_debug_symbol).in_file(&instr.file)),
+                };
+                limits.check_string(instr, a.len(), current_debug_symbol)?;
+
+                let res = match label {
+                    s if s == "int" => {
+                        match a.parse::<i32>() {
+                            Ok(i) => ValueType::Integer(i),
+                            Err(_) => match a.parse::<bool>() {
+                                Ok(b) => ValueType::Integer(b as i32),
+                                Err(_) => return Err(Error::new("Type: Invalid int or bool".to_string(), instr.line, instr.col, current_debug_symbol).in_file(&instr.file)),
+                            },
+                        }
+                    },
+                    s if s == "float" => {
+                        match a.parse::<f32>() {
+                            Ok(f) => ValueType::Float(f),
+                            Err(_) => return Err(Error::new("Type: Invalid float".to_string(), instr.line, instr.col, current_debug_symbol).in_file(&instr.file)),
+                        }
+                    },
+                    s if s == "str" => ValueType::String(a),
+                    s if s == "bool" => {
+                        match a.parse::<bool>() {
+                            Ok(b) => ValueType::Boolean(b),
+                            Err(_) => return Err(Error::new("Type: Invalid bool".to_string(), instr.line, instr.col, current_debug_symbol).in_file(&instr.file)),
+                        }
+                    },
+                    _ => return Err(Error::new("Type: Invalid type".to_string(), instr.line, instr.col, current_debug_symbol).in_file(&instr.file)),
+                };
+
+                stack.push(res);
+            },
+            InstructionKind::Ret => {
+                if let Some(i) = ret_stack.pop() {
+                    *cur = i;
+                    handlers.retain(|h| h.calls <= ret_stack.len());
+                } else {
+                    let a = stack.pop().ok_or(Error::new("Ret: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                    let code = a.to_int().map_err(|e| Error::new(e, instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                    *exit = Some(code);
+                    return Ok(Some(code));
+                }
+            },
+            InstructionKind::Run => {
+                let func = instr.params[0].clone();
+                let i = funcs.get(&func.to_string()).ok_or(Error::new("Run: Function not found", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                if ret_stack.len() >= limits.ret_stack {
+                    let message = format!("Run: Return stack limit of {} calls exceeded", limits.ret_stack);
+                    return Err(Error::new(message, instr.line, instr.col, current_debug_symbol).in_file(&instr.file).with_kind(ErrorKind::LimitExceeded));
+                }
+                ret_stack.push(*cur);
+                *cur = *i;
+
+            },
+            InstructionKind::Sys => {
+                let syscall_number = stack.pop().ok_or(Error::new("Sys: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?.clone();
+                let mut args = Vec::new();
+
+                for _ in 0..6 {
+                    if let Some(arg) = stack.pop() {
+                        args.push(arg);
+                    } else {
+                        args.push(ValueType::Integer(0)); // Default to 0 if not enough arguments
+                    }
+                }
+
+                let result = match syscall_number {
+                    ValueType::Integer(num) => {
+                        let syscall_args = args.iter().map(|arg| match arg {
+                            ValueType::Integer(i) => Ok(*i as usize),
+                            ValueType::Float(f) => Ok(*f as usize),
+                            ValueType::Boolean(b) => Ok(*b as usize),
+                            ValueType::String(s) => {
+                                Ok(s.as_ptr() as usize)
+                            },
+                            ValueType::Buffer(b) => {
+                                let buf = bufs.get(b).ok_or(Error::new("Sys: Buffer not found", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                                Ok(buf.ptr)
+                            },
+                            ValueType::Variable(v) => {
+                                Ok(v.len())
+                            }
+                            ValueType::DebugSymbol(_) => {
+                                Err(Error::new("Sys: Debug symbol not allowed".to_string(), instr.line, instr.col, current_debug_symbol).in_file(&instr.file))
+                            }
+                            ValueType::List(_) => {
+                                Err(Error::new("Sys: List not allowed".to_string(), instr.line, instr.col, current_debug_symbol).in_file(&instr.file))
+                            }
+                            ValueType::Map(_) => {
+                                Err(Error::new("Sys: Map not allowed".to_string(), instr.line, instr.col, current_debug_symbol).in_file(&instr.file))
+                            }
+                        }).collect::<Result<Vec<usize>, Error>>()?;
+
+                        let syscall_args = syscalls::SyscallArgs::new(syscall_args[0], syscall_args[1], syscall_args[2], syscall_args[3], syscall_args[4], syscall_args[5]);
+                        // Unknown and negative numbers fail like an unimplemented syscall.
+                        match syscalls::Sysno::new(num as usize) {
+                            Some(sysno) => unsafe { syscalls::syscall(sysno, &syscall_args) },
+                            None => Err(syscalls::Errno::ENOSYS),
+                        }
+                    },
+                    _ => return Err(Error::new("Sys: Invalid syscall number type".to_string(), instr.line, instr.col, current_debug_symbol).in_file(&instr.file)),
+                };
+
+                stack.push(ValueType::Integer(match result {
+                    Ok(value) => value as i32,
+                    Err(errno) => -errno.into_raw(),
+                }));
+            },
+            InstructionKind::Ern => {
+                let a = stack.pop().ok_or(Error::new("Ern: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                let errno = a.to_int().map_err(|e| Error::new(format!("Ern: {}", e), instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                let name = syscalls::Errno::new(errno.saturating_abs()).name().map(|n| n.to_string()).unwrap_or(format!("Unknown errno {}", errno.saturating_abs()));
+                stack.push(ValueType::String(name));
+            },
+            InstructionKind::Len => {
+                let a = stack.last().ok_or(Error::new("Len: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?.clone();
+                let len = match a {
+                    ValueType::String(s) => s.len(),
+                    ValueType::Buffer(b) => {
+                        let buf = bufs.get(&b).ok_or(Error::new("Len: Buffer not found", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?.clone();
+                        buf.size
+                    },
+                    ValueType::List(l) => l.borrow().len(),
+                    ValueType::Map(m) => m.borrow().len(),
+                    _ => return Err(Error::new("Len: Invalid type".to_string(), instr.line, instr.col, current_debug_symbol).in_file(&instr.file)),
+                };
+                stack.push(ValueType::Integer(len as i32));
+            },
+            InstructionKind::Lnw => {
+                stack.push(ValueType::List(Rc::new(RefCell::new(Vec::new()))));
+            },
+            InstructionKind::Lpu => {
+                let a = stack.pop().ok_or(Error::new("Lpu: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                let list = top_list(stack, "Lpu", instr, current_debug_symbol)?;
+                let depth = nesting(&a, Rc::as_ptr(&list) as *const ())
+                    .ok_or(Error::new("Lpu: Cannot add a list to itself", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                limits.check_depth("Lpu", instr, depth + 1, current_debug_symbol)?;
+                limits.check_items("Lpu", instr, list.borrow().len() + 1, current_debug_symbol)?;
+                list.borrow_mut().push(a);
+            },
+            InstructionKind::Lpo => {
+                let list = top_list(stack, "Lpo", instr, current_debug_symbol)?;
+                let a = list.borrow_mut().pop().ok_or(Error::new("Lpo: List is empty", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                stack.push(a);
+            },
+            InstructionKind::Lgt => {
+                let index = pop_index(stack, "Lgt", instr, current_debug_symbol)?;
+                let list = top_list(stack, "Lgt", instr, current_debug_symbol)?;
+                let a = list.borrow().get(index).cloned()
+                    .ok_or(Error::new(format!("Lgt: Index {} out of range", index), instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                stack.push(a);
+            },
+            InstructionKind::Lst => {
+                let a = stack.pop().ok_or(Error::new("Lst: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                let index = pop_index(stack, "Lst", instr, current_debug_symbol)?;
+                let list = top_list(stack, "Lst", instr, current_debug_symbol)?;
+                let depth = nesting(&a, Rc::as_ptr(&list) as *const ())
+                    .ok_or(Error::new("Lst: Cannot add a list to itself", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                limits.check_depth("Lst", instr, depth + 1, current_debug_symbol)?;
+                let mut items = list.borrow_mut();
+                let item = items.get_mut(index)
+                    .ok_or(Error::new(format!("Lst: Index {} out of range", index), instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                *item = a;
+            },
+            InstructionKind::Lsl => {
+                let end = pop_index(stack, "Lsl", instr, current_debug_symbol)?;
+                let start = pop_index(stack, "Lsl", instr, current_debug_symbol)?;
+                let list = top_list(stack, "Lsl", instr, current_debug_symbol)?;
+                let slice = list.borrow().get(start..end).map(|items| items.to_vec())
+                    .ok_or(Error::new(format!("Lsl: Range {}..{} out of range", start, end), instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                limits.check_items("Lsl", instr, slice.len(), current_debug_symbol)?;
+                stack.push(ValueType::List(Rc::new(RefCell::new(slice))));
+            },
+            InstructionKind::Mnw => {
+                stack.push(ValueType::Map(Rc::new(RefCell::new(BTreeMap::new()))));
+            },
+            InstructionKind::Mst => {
+                let a = stack.pop().ok_or(Error::new("Mst: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                let key = pop_key(stack, "Mst", instr, current_debug_symbol)?;
+                let map = top_map(stack, "Mst", instr, current_debug_symbol)?;
+                let depth = nesting(&a, Rc::as_ptr(&map) as *const ())
+                    .ok_or(Error::new("Mst: Cannot add a map to itself", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                limits.check_depth("Mst", instr, depth + 1, current_debug_symbol)?;
+                let len = map.borrow().len() + !map.borrow().contains_key(&key) as usize;
+                limits.check_items("Mst", instr, len, current_debug_symbol)?;
+                map.borrow_mut().insert(key, a);
+            },
+            InstructionKind::Mgt => {
+                let default = stack.pop().ok_or(Error::new("Mgt: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                let key = pop_key(stack, "Mgt", instr, current_debug_symbol)?;
+                let map = top_map(stack, "Mgt", instr, current_debug_symbol)?;
+                let a = map.borrow().get(&key).cloned().unwrap_or(default);
+                stack.push(a);
+            },
+            InstructionKind::Mrm => {
+                let key = pop_key(stack, "Mrm", instr, current_debug_symbol)?;
+                let map = top_map(stack, "Mrm", instr, current_debug_symbol)?;
+                map.borrow_mut().remove(&key);
+            },
+            InstructionKind::Mhs => {
+                let key = pop_key(stack, "Mhs", instr, current_debug_symbol)?;
+                let map = top_map(stack, "Mhs", instr, current_debug_symbol)?;
+                let has = map.borrow().contains_key(&key);
+                stack.push(ValueType::Boolean(has));
+            },
+            InstructionKind::Mks => {
+                let map = top_map(stack, "Mks", instr, current_debug_symbol)?;
+                limits.check_items("Mks", instr, map.borrow().len(), current_debug_symbol)?;
+                let keys = map.borrow().keys().map(MapKey::to_value).collect();
+                stack.push(ValueType::List(Rc::new(RefCell::new(keys))));
+            },
+            InstructionKind::Ast => {
+                let a = stack.pop().ok_or(Error::new("Ast: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                let truthy = a.to_bool().map_err(|e| Error::new(format!("Ast: {}", e), instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                if !truthy {
+                    let message = match instr.params.first() {
+                        Some(message) => format!("Assertion failed: {}", message),
+                        None => "Assertion failed".to_string(),
+                    };
+                    return Err(Error::new(message, instr.line, instr.col, current_debug_symbol).in_file(&instr.file).with_kind(ErrorKind::Panic).with_backtrace(backtrace(program, *cur, ret_stack)));
+                }
+            },
+            InstructionKind::Pnc => {
+                let message = format!("Panic: {}", instr.params[0]);
+                return Err(Error::new(message, instr.line, instr.col, current_debug_symbol).in_file(&instr.file).with_kind(ErrorKind::Panic).with_backtrace(backtrace(program, *cur, ret_stack)));
+            },
+            InstructionKind::Try => {
+                let label = instr.params[0].clone();
+                let i = labels.get(&label.to_string()).ok_or(Error::new("Try: Label not found", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                handlers.push(Handler { label: *i, stack: stack.len(), calls: ret_stack.len() });
+            },
+            InstructionKind::End => {
+                handlers.pop().ok_or(Error::new("End: No active try", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+            },
+            InstructionKind::Thr => {
+                let a = stack.pop().ok_or(Error::new("Thr: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                match handlers.pop() {
+                    Some(handler) => unwind(handler, stack, ret_stack, cur, a),
+                    None => return Err(Error::new(format!("Uncaught exception: {}", crate::disasm::literal(&a)), instr.line, instr.col, current_debug_symbol).in_file(&instr.file)),
+                }
+            },
+            InstructionKind::Agc => {
+                stack.push(ValueType::Integer(args.len() as i32));
+            },
+            InstructionKind::Arg => {
+                let a = stack.pop().ok_or(Error::new("Arg: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                let index = a.to_int().map_err(|e| Error::new(format!("Arg: {}", e), instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                let arg = usize::try_from(index).ok().and_then(|i| args.get(i))
+                    .ok_or(Error::new(format!("Arg: Index {} out of range", index), instr.line, instr.col, current_debug_symbol).in_file(&instr.file))?;
+                limits.check_string(instr, arg.len(), current_debug_symbol)?;
+                stack.push(ValueType::String(arg.clone()));
+            },
+            InstructionKind::Env => {
+                let name = match stack.pop().ok_or(Error::new("Env: Stack underflow", instr.line, instr.col, current_debug_symbol).in_file(&instr.file))? {
+                    ValueType::String(s) => s,
+                    _ => return Err(Error::new("Env: Invalid type".to_string(), instr.line, instr.col, current_debug_symbol).in_file(&instr.file)),
+                };
+                let value = env.get(&name).cloned().unwrap_or_default();
+                limits.check_string(instr, value.len(), current_debug_symbol)?;
+                stack.push(ValueType::String(value));
+            },
+            InstructionKind::Fre => {
+                let a = instr.params[0].clone();
+                match a {
+                    ValueType::Buffer(b) => {
+                        bufs.remove(&b);
+                    },
+                    ValueType::Variable(v) => {
+                        vars.remove(&v);
+                    },
+                    _ => return Err(Error::new("Fre: Invalid type".to_string(), instr.line, instr.col, current_debug_symbol).in_file(&instr.file)),
+                };
+            }
+            InstructionKind::Lbl => {}
+            InstructionKind::Fun => {}
+            InstructionKind::Alc => {
+                let name = instr.params[0].clone().to_string();
+                let used: usize = bufs.iter().filter(|(n, _)| **n != name).map(|(_, b)| b.size).sum();
+                let buffer = allocate(instr, current_debug_symbol, limits.buffer_bytes.saturating_sub(used))?;
+                bufs.insert(name, buffer);
+            },
+            InstructionKind::DebugSymbol => {
+                let debug_symbol = instr.params[0].as_debug_symbol().unwrap();
+                *current_debug_symbol = Some(debug_symbol);
+            }
+        }
+
+        if stack.len() > limits.stack {
+            let message = format!("Stack limit of {} values exceeded", limits.stack);
+            return Err(Error::new(message, instr.line, instr.col, current_debug_symbol).in_file(&instr.file).with_kind(ErrorKind::LimitExceeded));
+        }
+
+        *cur += 1;
+        Ok(None)
+    }
+
+    pub fn run(&mut self) -> Result<i32, Error> {
+        loop {
+            if let Some(code) = self.step()? {
+                return Ok(code);
+            }
+        }
+    }
+}
+
+pub fn evaluate(parsed: ParserRet) -> Result<(Vec<ValueType>, i32), Error> {
+    let mut vm = Vm::new(parsed)?;
+    let code = vm.run()?;
+    Ok((vm.stack, code))
 }
\ No newline at end of file
@@ -0,0 +1,164 @@
+use crate::Error;
+use crate::disasm;
+use crate::lexer::{self, Token, TokenValue};
+
+struct Line {
+    indent: Option<usize>,
+    code: String,
+    comment: Option<String>,
+    instruction: bool,
+    blank_before: bool,
+}
+
+// Numbers keep their original spelling (`0x1F`, `'a'`, `1e3`) as long as it lexes back to the same value.
+fn number(source: &[Vec<char>], t: &Token) -> String {
+    let line = source.get(t.line - 1).map(|l| l.as_slice()).unwrap_or(&[]);
+    let mut end = t.col + 1;
+    if line.get(t.col) == Some(&'\'') {
+        if line.get(end) == Some(&'\\') {
+            end += 1;
+        }
+        end += 1;
+        while end < line.len() && line[end] != '\'' {
+            end += 1;
+        }
+        end += 1;
+    } else {
+        while end < line.len() && (line[end].is_alphanumeric() || ".+-_".contains(line[end])) {
+            end += 1;
+        }
+    }
+
+    let raw: String = line.get(t.col..end.min(line.len())).unwrap_or(&[]).iter().collect();
+    match lexer::lex(raw.clone()) {
+        Ok(tokens) if tokens.len() == 1 && tokens[0].value == t.value => raw,
+        _ => match &t.value {
+            TokenValue::Float(f) => format!("{:?}", f),
+            value => value.to_string(),
+        },
+    }
+}
+
+fn render(source: &[Vec<char>], t: &Token) -> String {
+    match &t.value {
+        TokenValue::Integer(_) | TokenValue::Float(_) => number(source, t),
+        TokenValue::String(s) => format!("\"{}\"", disasm::escape(s)),
+        TokenValue::DebugSymbol(ds) => format!("<{}:{}:{}>", ds.path, ds.line, ds.col),
+        value => value.to_string(),
+    }
+}
+
+fn join(source: &[Vec<char>], tokens: &[&Token]) -> String {
+    let mut out = String::new();
+    for (k, t) in tokens.iter().enumerate() {
+        if k > 0 {
+            let previous = &tokens[k - 1].value;
+            let attached = matches!(t.value, TokenValue::Punctuation(',' | ':' | ')'))
+                || (t.value == TokenValue::Punctuation('(') && matches!(previous, TokenValue::Identifier(_)))
+                || *previous == TokenValue::Punctuation('(');
+            if !attached {
+                out.push(' ');
+            }
+        }
+        out.push_str(&render(source, t));
+    }
+    out
+}
+
+// Reformats `.zvm` source: functions, directives and sections start at column 0, labels are indented
+// one level inside functions and macros, and instructions one level deeper than the label they follow.
+// Operands are separated by single spaces, strings are re-escaped, comments are kept and a debug
+// symbol on its own line is moved to the end of the instruction before it.
+pub fn format(code: &str) -> Result<String, Error> {
+    let tokens = lexer::lex_with_comments(code.to_string())?;
+    let source: Vec<Vec<char>> = code.split('\n').map(|l| l.chars().collect()).collect();
+
+    let mut groups: Vec<Vec<&Token>> = Vec::new();
+    for t in &tokens {
+        match groups.last_mut() {
+            Some(group) if group[0].line == t.line => group.push(t),
+            _ => groups.push(vec![t]),
+        }
+    }
+
+    let mut lines: Vec<Line> = Vec::new();
+    let mut in_block = false;
+    let mut under_label = false;
+    let mut previous_line = 0;
+
+    for group in groups {
+        let blank_before = !lines.is_empty() && group[0].line > previous_line + 1;
+        previous_line = group[0].line;
+
+        let (code_tokens, comment) = match group.last() {
+            Some(t) if t.kind == "comment" => (&group[..group.len() - 1], Some(t.value.to_string())),
+            _ => (&group[..], None),
+        };
+
+        let Some(first) = code_tokens.first() else {
+            lines.push(Line { indent: None, code: String::new(), comment, instruction: false, blank_before });
+            continue;
+        };
+
+        let defines = code_tokens.get(1).is_some_and(|t| t.value == TokenValue::Punctuation(':'));
+        let body = if in_block { 4 } else { 0 };
+        let (indent, instruction) = match first.kind {
+            "function" if defines => {
+                in_block = true;
+                under_label = false;
+                (0, false)
+            },
+            "label" if defines => {
+                under_label = true;
+                (body, false)
+            },
+            "label" | "directive" => {
+                let opens = [TokenValue::Label(".data".to_string()), TokenValue::Directive("%macro".to_string())];
+                if opens.contains(&first.value) || first.value == TokenValue::Directive("%endmacro".to_string()) {
+                    in_block = opens.contains(&first.value);
+                    under_label = false;
+                }
+                (0, false)
+            },
+            _ => (body + if under_label { 4 } else { 0 }, true),
+        };
+
+        let code = join(&source, code_tokens);
+        let attach = first.kind == "debugsymbol" && code_tokens.len() == 1 && comment.is_none() && !blank_before
+            && lines.last().is_some_and(|l| l.instruction);
+        if attach {
+            let last = lines.last_mut().unwrap();
+            last.code.push(' ');
+            last.code.push_str(&code);
+            continue;
+        }
+
+        lines.push(Line { indent: Some(indent), code, comment, instruction, blank_before });
+    }
+
+    let mut next_indent = 0;
+    for line in lines.iter_mut().rev() {
+        match line.indent {
+            Some(indent) => next_indent = indent,
+            None => line.indent = Some(next_indent),
+        }
+    }
+
+    let mut out = String::new();
+    for line in &lines {
+        if line.blank_before {
+            out.push('\n');
+        }
+        out.push_str(&" ".repeat(line.indent.unwrap_or(0)));
+        out.push_str(&line.code);
+        if let Some(comment) = &line.comment {
+            if !line.code.is_empty() {
+                out.push(' ');
+            }
+            out.push_str(comment);
+        }
+        out.push('\n');
+    }
+
+    Ok(out)
+}
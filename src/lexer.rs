@@ -1,194 +1,350 @@
-use std::fmt;
-use crate::Error;
-
-#[derive(Clone, Debug, PartialEq)]
-pub struct DebugSymbol {
-    pub path: String,
-    pub line: usize,
-    pub col: usize,
-}
-
-#[derive(Debug, PartialEq, Clone)]
-pub enum TokenValue {
-    Identifier(String),
-    Label(String),
-    Function(String),
-    Integer(i32),
-    Float(f32),
-    String(String),
-    Punctuation(char),
-    Buffer(String),
-    Variable(String),
-    DebugSymbol(DebugSymbol),
-}
-
-impl TokenValue {
-    pub fn as_debug_symbol(&self) -> Result<DebugSymbol, String> {
-        match self {
-            TokenValue::DebugSymbol(ds) => Ok(ds.clone()),
-            _ => Err("Expected debug symbol".to_owned()),
-        }
-    }
-}
-
-impl fmt::Display for TokenValue {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            TokenValue::Integer(i) => write!(f, "{}", i),
-            TokenValue::Float(fl) => write!(f, "{}", fl),
-            TokenValue::String(s) => write!(f, "{}", s),
-            TokenValue::Identifier(id) => write!(f, "{}", id),
-            TokenValue::Label(l) => write!(f, "{}", l),
-            TokenValue::Punctuation(p) => write!(f, "{}", p),
-            TokenValue::Function(fn_name) => write!(f, "{}", fn_name),
-            TokenValue::Buffer(b) => write!(f, "{}", b),
-            TokenValue::Variable(v) => write!(f, "{}", v),
-            TokenValue::DebugSymbol(ds) => write!(f, "{}:{}:{}", ds.path, ds.line, ds.col),
-        }
-    }
-}
-
-#[derive(Debug, PartialEq, Clone)]
-pub struct Token {
-    pub kind: &'static str,
-    pub value: TokenValue,
-    pub line: usize,
-    pub col: usize,
-}
-
-fn until<F>(chars: &Vec<char>, start: usize, check: F) -> (String, usize)
-where
-    F: Fn(char) -> bool
-{
-    let mut cur = start;
-    let mut value = String::new();
-    while cur < chars.len() && check(chars[cur]) {
-        if chars[cur] == '\\' && cur + 1 < chars.len() {
-            cur += 1;
-            match chars[cur] {
-                'n' => value.push('\n'),
-                't' => value.push('\t'),
-                '\\' => value.push('\\'),
-                '"' => value.push('"'),
-                _ => value.push(chars[cur]),
-            }
-            cur += 1;
-        } else {
-            value.push(chars[cur]);
-            cur += 1;
-        }
-    }
-
-    (value, cur)
-}
-
-fn could_be(c: char, s: &str) -> bool {
-    s.chars().any(|x| x == c)
-}
-
-pub fn lex(input: String) -> Result<Vec<Token>, Error> {
-    let chars: Vec<char> = input.chars().collect();
-    let mut tokens: Vec<Token> = vec![];
-    let mut cur = 0;
-
-    let mut line = 1;
-    let mut col = 0;
-
-    while cur < chars.len() {
-        let c = chars[cur];
-        if c.is_alphabetic() {
-            let value = until(&chars, cur, |c| c.is_alphanumeric() || c == '_');
-            tokens.push(Token { kind: "identifier", value: TokenValue::Identifier(value.clone().0), line, col });
-            cur = value.1;
-            col += value.0.len();
-        } else if c == '.' && cur + 1 < chars.len() && chars[cur + 1].is_alphabetic() {
-            cur += 1;
-            let value = until(&chars, cur, |c| c.is_alphanumeric() || c == '_');
-            tokens.push(Token { kind: "label", value: TokenValue::Label(".".to_owned() + &*value.0), line, col });
-            cur = value.1;
-            col += value.0.len() + 1;
-        } else if c == '@' {
-            let value = until(&chars, cur + 1, |c| c.is_alphanumeric() || c == '_');
-            tokens.push(Token { kind: "function", value: TokenValue::Function("@".to_owned() + &*value.0), line, col });
-            cur = value.1;
-            col += value.0.len() + 1;
-        } else if c == '*' {
-            let value = until(&chars, cur + 1, |c| c.is_alphanumeric() || c == '_');
-            tokens.push(Token { kind: "buffer", value: TokenValue::Buffer("*".to_owned() + &*value.0), line, col });
-            cur = value.1;
-            col += value.0.len() + 1;
-        } else if c == '$' {
-            let value = until(&chars, cur + 1, |c| c.is_alphanumeric() || c == '_');
-            tokens.push(Token { kind: "variable", value: TokenValue::Variable("$".to_owned() + &*value.0), line, col });
-            cur = value.1;
-            col += value.0.len() + 1;
-        } else if c.is_digit(10) || c == '.' {
-            let value = until(&chars, cur, |c| c.is_digit(10) || c == '.');
-            if value.0.contains('.') {
-                let float_value: f32 = value.0.parse().map_err(|_| Error::new(format!("Invalid float: '{}'", value.0), line, col, &None))?;
-                tokens.push(Token { kind: "float", value: TokenValue::Float(float_value), line, col });
-            } else {
-                let integer_value: i32 = value.0.parse().map_err(|_| Error::new(format!("Invalid integer: '{}'", value.0), line, col, &None))?;
-                tokens.push(Token { kind: "integer", value: TokenValue::Integer(integer_value), line, col });
-            }
-            cur = value.1;
-            col += value.0.len();
-        } else if c == '"' {
-            let value = until(&chars, cur + 1, |c| c != '"');
-            let string_value = value.0;
-            cur = value.1;
-            col += string_value.len();
-
-            if cur >= chars.len() || chars[cur] != '"' {
-                Err(Error::new("Unterminated string".to_owned(), line, col, &None))?;
-            }
-
-            cur += 1;
-            col += 1;
-
-            tokens.push(Token { kind: "string", value: TokenValue::String(string_value), line, col });
-        } else if c == '<' {
-            let value = until(&chars, cur + 1, |c| c!= '>');
-            let debug_symbol = value.0;
-            cur = value.1;
-            col += debug_symbol.len();
-
-            if cur >= chars.len() || chars[cur] != '>' {
-                Err(Error::new("Unterminated debug symbol".to_owned(), line, col, &None))?;
-            }
-
-            cur += 1;
-            col += 1;
-
-            let parts: Vec<&str> = debug_symbol.split(':').collect();
-            if parts.len() != 3 {
-                Err(Error::new("Invalid debug symbol".to_owned(), line, col, &None))?;
-            }
-
-            let db_path = parts[0].to_owned();
-            let db_line = parts[1].parse().map_err(|_| Error::new("Invalid line number".to_owned(), line, col, &None))?;
-            let db_col = parts[2].parse().map_err(|_| Error::new("Invalid column number".to_owned(), line, col, &None))?;
-
-            tokens.push(Token { kind: "debugsymbol", value: TokenValue::DebugSymbol(DebugSymbol {
-                path: db_path,
-                line: db_line,
-                col: db_col,
-            }), line, col });
-        } else if could_be(c, ":,") {
-            tokens.push(Token { kind: "punctuation", value: TokenValue::Punctuation(c), line, col });
-            cur += 1;
-            col += 1;
-        } else if c == '\n' {
-            line += 1;
-            cur += 1;
-            col = 0;
-        } else if c.is_whitespace() {
-            cur += 1;
-            col += 1;
-        } else {
-            Err(Error::new(format!("Unexpected character: '{}'", c), line, col, &None))?;
-        }
-    }
-
-    Ok(tokens)
+use std::fmt;
+use crate::Error;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DebugSymbol {
+    pub path: String,
+    pub line: usize,
+    pub col: usize,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum TokenValue {
+    Identifier(String),
+    Label(String),
+    Function(String),
+    Integer(i32),
+    Float(f32),
+    String(String),
+    Punctuation(char),
+    Buffer(String),
+    Variable(String),
+    Directive(String),
+    Comment(String),
+    DebugSymbol(DebugSymbol),
+}
+
+impl TokenValue {
+    pub fn as_debug_symbol(&self) -> Result<DebugSymbol, String> {
+        match self {
+            TokenValue::DebugSymbol(ds) => Ok(ds.clone()),
+            _ => Err("Expected debug symbol".to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for TokenValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenValue::Integer(i) => write!(f, "{}", i),
+            TokenValue::Float(fl) => write!(f, "{}", fl),
+            TokenValue::String(s) => write!(f, "{}", s),
+            TokenValue::Identifier(id) => write!(f, "{}", id),
+            TokenValue::Label(l) => write!(f, "{}", l),
+            TokenValue::Punctuation(p) => write!(f, "{}", p),
+            TokenValue::Function(fn_name) => write!(f, "{}", fn_name),
+            TokenValue::Buffer(b) => write!(f, "{}", b),
+            TokenValue::Variable(v) => write!(f, "{}", v),
+            TokenValue::Directive(d) => write!(f, "{}", d),
+            TokenValue::Comment(c) => write!(f, ";{}", c),
+            TokenValue::DebugSymbol(ds) => write!(f, "{}:{}:{}", ds.path, ds.line, ds.col),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Token {
+    pub kind: &'static str,
+    pub value: TokenValue,
+    pub line: usize,
+    pub col: usize,
+    pub file: Option<String>,
+}
+
+fn until<F>(chars: &[char], start: usize, check: F) -> (String, usize)
+where
+    F: Fn(char) -> bool
+{
+    let mut cur = start;
+    let mut value = String::new();
+    while cur < chars.len() && check(chars[cur]) {
+        if chars[cur] == '\\' && cur + 1 < chars.len() {
+            cur += 1;
+            match chars[cur] {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                '\\' => value.push('\\'),
+                '"' => value.push('"'),
+                _ => value.push(chars[cur]),
+            }
+            cur += 1;
+        } else {
+            value.push(chars[cur]);
+            cur += 1;
+        }
+    }
+
+    (value, cur)
+}
+
+fn could_be(c: char, s: &str) -> bool {
+    s.chars().any(|x| x == c)
+}
+
+// Reads a name that may be qualified with a module, as in `math::sqrt`.
+fn qualified_name(chars: &[char], start: usize) -> (String, usize) {
+    let (mut name, mut cur) = until(chars, start, |c| c.is_alphanumeric() || c == '_');
+    while chars.get(cur) == Some(&':') && chars.get(cur + 1) == Some(&':') && chars.get(cur + 2).is_some_and(|c| c.is_alphabetic()) {
+        let part = until(chars, cur + 2, |c| c.is_alphanumeric() || c == '_');
+        name = name + "::" + &part.0;
+        cur = part.1;
+    }
+    (name, cur)
+}
+
+fn starts_number(chars: &[char], cur: usize) -> bool {
+    match chars.get(cur) {
+        Some(c) if c.is_ascii_digit() => true,
+        Some('.') => chars.get(cur + 1).is_some_and(|c| c.is_ascii_digit()),
+        _ => false,
+    }
+}
+
+// Reads the escape sequence following a backslash at `start`, returning the character and the index after it.
+fn escape(chars: &[char], start: usize, line: usize, col: usize) -> Result<(char, usize), Error> {
+    let c = *chars.get(start).ok_or(Error::new("Unterminated escape sequence".to_owned(), line, col, &None))?;
+    match c {
+        'n' => Ok(('\n', start + 1)),
+        't' => Ok(('\t', start + 1)),
+        'r' => Ok(('\r', start + 1)),
+        '0' => Ok(('\0', start + 1)),
+        'x' => {
+            // Always two digits, so hex digits right after the escape are ordinary characters.
+            let digits: String = chars.iter().skip(start + 1).take(2).collect();
+            if digits.len() != 2 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(Error::new(format!("Invalid escape sequence: '\\x{}'", digits), line, col, &None));
+            }
+            // Strings hold characters, not bytes, so only ASCII can be written this way; use `\u{..}` above it.
+            let value = u8::from_str_radix(&digits, 16).unwrap();
+            if !value.is_ascii() {
+                return Err(Error::new(format!("Invalid escape sequence: '\\x{}', use '\\u{{{}}}' above 7f", digits, digits), line, col, &None));
+            }
+            Ok((value as char, start + 3))
+        },
+        'u' => {
+            if chars.get(start + 1) != Some(&'{') {
+                return Err(Error::new("Expected '{' after '\\u'".to_owned(), line, col, &None));
+            }
+            let (digits, end) = until(chars, start + 2, |c| c.is_ascii_hexdigit());
+            if chars.get(end) != Some(&'}') {
+                return Err(Error::new(format!("Unterminated escape sequence: '\\u{{{}'", digits), line, col, &None));
+            }
+            let value = u32::from_str_radix(&digits, 16).ok().and_then(char::from_u32)
+                .ok_or(Error::new(format!("Invalid unicode escape: '\\u{{{}}}'", digits), line, col, &None))?;
+            Ok((value, end + 1))
+        },
+        _ => Ok((c, start + 1)),
+    }
+}
+
+// Reads an integer or float literal starting at `start`, including an optional sign, radix prefix and exponent.
+fn number(chars: &[char], start: usize, line: usize, col: usize) -> Result<(Token, usize), Error> {
+    let mut cur = start;
+    let negative = chars[cur] == '-';
+    if negative {
+        cur += 1;
+    }
+
+    let radix = match (chars.get(cur), chars.get(cur + 1)) {
+        (Some('0'), Some('x')) => 16,
+        (Some('0'), Some('b')) => 2,
+        (Some('0'), Some('o')) => 8,
+        _ => 10,
+    };
+
+    if radix != 10 {
+        let (digits, end) = until(chars, cur + 2, |c| c.is_ascii_alphanumeric());
+        let text: String = chars[start..end].iter().collect();
+        let magnitude = i64::from_str_radix(&digits, radix).map_err(|_| Error::new(format!("Invalid integer: '{}'", text), line, col, &None))?;
+        let value = if negative { -magnitude } else { magnitude };
+        let integer_value = i32::try_from(value).map_err(|_| Error::new(format!("Integer out of range: '{}'", text), line, col, &None))?;
+        return Ok((Token { kind: "integer", value: TokenValue::Integer(integer_value), line, col, file: None }, end));
+    }
+
+    let (_, mut end) = until(chars, cur, |c| c.is_ascii_digit() || c == '.');
+    if chars.get(end).is_some_and(|&c| c == 'e' || c == 'E') {
+        let mut exponent = end + 1;
+        if chars.get(exponent).is_some_and(|&c| c == '+' || c == '-') {
+            exponent += 1;
+        }
+        if chars.get(exponent).is_some_and(|c| c.is_ascii_digit()) {
+            end = until(chars, exponent, |c| c.is_ascii_digit()).1;
+        }
+    }
+
+    let text: String = chars[start..end].iter().collect();
+    if text.contains(['.', 'e', 'E']) {
+        let float_value: f32 = text.parse().map_err(|_| Error::new(format!("Invalid float: '{}'", text), line, col, &None))?;
+        if !float_value.is_finite() {
+            return Err(Error::new(format!("Float out of range: '{}'", text), line, col, &None));
+        }
+        Ok((Token { kind: "float", value: TokenValue::Float(float_value), line, col, file: None }, end))
+    } else {
+        let integer_value: i32 = text.parse().map_err(|_| Error::new(format!("Invalid integer: '{}'", text), line, col, &None))?;
+        Ok((Token { kind: "integer", value: TokenValue::Integer(integer_value), line, col, file: None }, end))
+    }
+}
+
+pub fn lex(input: String) -> Result<Vec<Token>, Error> {
+    tokenize(input, false)
+}
+
+// Like `lex`, but keeps `; comments` as tokens of kind "comment" for tools that rewrite source.
+pub fn lex_with_comments(input: String) -> Result<Vec<Token>, Error> {
+    tokenize(input, true)
+}
+
+fn tokenize(input: String, keep_comments: bool) -> Result<Vec<Token>, Error> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens: Vec<Token> = vec![];
+    let mut cur = 0;
+
+    let mut line = 1;
+    let mut col = 0;
+
+    while cur < chars.len() {
+        let c = chars[cur];
+        if c.is_alphabetic() {
+            let value = until(&chars, cur, |c| c.is_alphanumeric() || c == '_');
+            tokens.push(Token { kind: "identifier", value: TokenValue::Identifier(value.clone().0), line, col, file: None });
+            col += value.1 - cur;
+            cur = value.1;
+        } else if c == '.' && cur + 1 < chars.len() && chars[cur + 1].is_alphabetic() {
+            let value = qualified_name(&chars, cur + 1);
+            tokens.push(Token { kind: "label", value: TokenValue::Label(".".to_owned() + &*value.0), line, col, file: None });
+            col += value.1 - cur;
+            cur = value.1;
+        } else if c == '@' {
+            let value = qualified_name(&chars, cur + 1);
+            tokens.push(Token { kind: "function", value: TokenValue::Function("@".to_owned() + &*value.0), line, col, file: None });
+            col += value.1 - cur;
+            cur = value.1;
+        } else if c == '*' {
+            let value = until(&chars, cur + 1, |c| c.is_alphanumeric() || c == '_');
+            tokens.push(Token { kind: "buffer", value: TokenValue::Buffer("*".to_owned() + &*value.0), line, col, file: None });
+            col += value.1 - cur;
+            cur = value.1;
+        } else if c == '%' && cur + 1 < chars.len() && chars[cur + 1].is_alphabetic() {
+            let value = until(&chars, cur + 1, |c| c.is_alphanumeric() || c == '_');
+            tokens.push(Token { kind: "directive", value: TokenValue::Directive("%".to_owned() + &*value.0), line, col, file: None });
+            col += value.1 - cur;
+            cur = value.1;
+        } else if c == '$' {
+            let value = until(&chars, cur + 1, |c| c.is_alphanumeric() || c == '_');
+            tokens.push(Token { kind: "variable", value: TokenValue::Variable("$".to_owned() + &*value.0), line, col, file: None });
+            col += value.1 - cur;
+            cur = value.1;
+        } else if c.is_ascii_digit() || c == '.' || (c == '-' && starts_number(&chars, cur + 1)) {
+            let (token, end) = number(&chars, cur, line, col)?;
+            tokens.push(token);
+            col += end - cur;
+            cur = end;
+        } else if c == '\'' {
+            let (value, end) = if cur + 1 < chars.len() && chars[cur + 1] == '\\' {
+                escape(&chars, cur + 2, line, col)?
+            } else if cur + 1 < chars.len() {
+                (chars[cur + 1], cur + 2)
+            } else {
+                return Err(Error::new("Unterminated character literal".to_owned(), line, col, &None));
+            };
+
+            if end >= chars.len() || chars[end] != '\'' {
+                return Err(Error::new("Unterminated character literal".to_owned(), line, col, &None));
+            }
+
+            tokens.push(Token { kind: "integer", value: TokenValue::Integer(value as i32), line, col, file: None });
+            col += end + 1 - cur;
+            cur = end + 1;
+        } else if c == '"' {
+            let mut end = cur + 1;
+            let mut string_value = String::new();
+            while end < chars.len() && chars[end] != '"' {
+                if chars[end] == '\\' {
+                    let (value, next) = escape(&chars, end + 1, line, col)?;
+                    string_value.push(value);
+                    end = next;
+                } else {
+                    string_value.push(chars[end]);
+                    end += 1;
+                }
+            }
+
+            if end >= chars.len() {
+                Err(Error::new("Unterminated string".to_owned(), line, col, &None))?;
+            }
+
+            tokens.push(Token { kind: "string", value: TokenValue::String(string_value), line, col, file: None });
+            // Strings may span lines, and tokens after them are located by line and column.
+            match chars[cur..end].iter().rposition(|&c| c == '\n') {
+                Some(last) => {
+                    line += chars[cur..end].iter().filter(|&&c| c == '\n').count();
+                    col = end - (cur + last);
+                },
+                None => col += end + 1 - cur,
+            }
+            cur = end + 1;
+        } else if c == '<' {
+            let start = cur;
+            let value = until(&chars, cur + 1, |c| c!= '>');
+            let debug_symbol = value.0;
+            cur = value.1;
+
+            if cur >= chars.len() || chars[cur] != '>' {
+                Err(Error::new("Unterminated debug symbol".to_owned(), line, col, &None))?;
+            }
+
+            cur += 1;
+
+            let parts: Vec<&str> = debug_symbol.split(':').collect();
+            if parts.len() != 3 {
+                Err(Error::new("Invalid debug symbol".to_owned(), line, col, &None))?;
+            }
+
+            let db_path = parts[0].to_owned();
+            let db_line = parts[1].parse().map_err(|_| Error::new("Invalid line number".to_owned(), line, col, &None))?;
+            let db_col = parts[2].parse().map_err(|_| Error::new("Invalid column number".to_owned(), line, col, &None))?;
+
+            tokens.push(Token { kind: "debugsymbol", value: TokenValue::DebugSymbol(DebugSymbol {
+                path: db_path,
+                line: db_line,
+                col: db_col,
+            }), line, col, file: None });
+            col += cur - start;
+        } else if could_be(c, ":,=()[]{}") {
+            tokens.push(Token { kind: "punctuation", value: TokenValue::Punctuation(c), line, col, file: None });
+            cur += 1;
+            col += 1;
+        } else if c == ';' {
+            let start = cur;
+            while cur < chars.len() && chars[cur] != '\n' {
+                cur += 1;
+            }
+            if keep_comments {
+                let comment: String = chars[start + 1..cur].iter().collect();
+                tokens.push(Token { kind: "comment", value: TokenValue::Comment(comment.trim_end().to_string()), line, col, file: None });
+            }
+            col += cur - start;
+        } else if c == '\n' {
+            line += 1;
+            cur += 1;
+            col = 0;
+        } else if c.is_whitespace() {
+            cur += 1;
+            col += 1;
+        } else {
+            Err(Error::new(format!("Unexpected character: '{}'", c), line, col, &None))?;
+        }
+    }
+
+    Ok(tokens)
 }
\ No newline at end of file
@@ -0,0 +1,108 @@
+use crate::lexer::DebugSymbol;
+
+pub mod parser;
+pub mod lexer;
+pub mod evaluator;
+pub mod preprocessor;
+pub mod linker;
+pub mod disasm;
+pub mod formatter;
+pub mod lint;
+pub mod debugger;
+pub mod tracer;
+pub mod profiler;
+pub mod coverage;
+pub mod snapshot;
+pub mod repl;
+pub mod tester;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    Other,
+    OutOfFuel,
+    Timeout,
+    LimitExceeded,
+    // Raised by `ast` and `pnc`, never caught by `try`.
+    Panic,
+}
+
+pub struct Error {
+    pub message: String,
+    pub kind: ErrorKind,
+    pub file: Option<String>,
+    pub line: usize,
+    pub col: usize,
+    // Boxed, since most errors have neither a debug symbol nor a backtrace.
+    pub context: Option<Box<ErrorContext>>,
+}
+
+#[derive(Default)]
+pub struct ErrorContext {
+    pub debug_symbol: Option<DebugSymbol>,
+    // Innermost call first, set by `ast` and `pnc`.
+    pub backtrace: Vec<String>,
+}
+
+impl std::fmt::Debug for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let location = if let Some(file) = &self.file {
+            format!("{}:{}:{}", file, self.line, self.col)
+        } else {
+            format!("{}:{}", self.line, self.col)
+        };
+
+        if let Some(ds) = self.debug_symbol() {
+            write!(f, "{} near {}:{}:{} ({})", self.message, ds.path, ds.line, ds.col, location)?;
+        } else {
+            write!(f, "{} near {}", self.message, location)?;
+        }
+        for frame in self.backtrace() {
+            write!(f, "\n    at {}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error {
+    pub fn new<S: Into<String>>(message: S, line: usize, col: usize, debug_symbol: &Option<DebugSymbol>) -> Self {
+        Self {
+            message: message.into(),
+            kind: ErrorKind::Other,
+            file: None,
+            line,
+            col,
+            context: debug_symbol.as_ref().map(|ds| Box::new(ErrorContext {
+                debug_symbol: Some(ds.clone()),
+                backtrace: Vec::new(),
+            })),
+        }
+    }
+
+    pub fn in_file(mut self, file: &Option<String>) -> Self {
+        if self.file.is_none() {
+            self.file = file.clone();
+        }
+        self
+    }
+
+    pub fn with_kind(mut self, kind: ErrorKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    pub fn with_backtrace(mut self, backtrace: Vec<String>) -> Self {
+        self.context.get_or_insert_with(Default::default).backtrace = backtrace;
+        self
+    }
+
+    pub fn debug_symbol(&self) -> Option<&DebugSymbol> {
+        self.context.as_ref().and_then(|c| c.debug_symbol.as_ref())
+    }
+
+    pub fn backtrace(&self) -> &[String] {
+        self.context.as_ref().map(|c| c.backtrace.as_slice()).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests;
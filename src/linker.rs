@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use crate::Error;
+use crate::parser::{Instruction, InstructionKind, ParserRet, ValueType};
+
+// Prefixes a function or label name with its module, so `@sqrt` in module `math` becomes `@math::sqrt`.
+fn qualify(module: &Option<String>, name: &str) -> String {
+    match module {
+        Some(module) if name != "@entry" => format!("{}{}::{}", &name[..1], module, &name[1..]),
+        _ => name.to_string(),
+    }
+}
+
+// Labels of files without a `%module` are made local to their file when there are several of them,
+// like the labels of macro expansions.
+fn local_label(module: &ParserRet, file: Option<usize>, label: &str) -> String {
+    match (&module.module, file) {
+        (None, Some(file)) => format!("{}__file{}", label, file),
+        _ => qualify(&module.module, label),
+    }
+}
+
+fn split(name: &str) -> Option<(&str, String)> {
+    let (module, local) = name[1..].split_once("::")?;
+    Some((module, format!("{}{}", &name[..1], local)))
+}
+
+fn resolve(modules: &[ParserRet], current: &ParserRet, instr: &Instruction) -> Result<String, Error> {
+    let target = instr.params[0].to_string();
+    let error = |message: String| Error::new(message, instr.line, instr.col, &None).in_file(&instr.file);
+
+    // Already linked programs, like the output of `compile`, define functions under their qualified names.
+    if split(&target).is_some() && current.funcs.contains_key(&target) {
+        return Ok(qualify(&current.module, &target));
+    }
+
+    if let Some((name, local)) = split(&target) {
+        if current.module.as_deref() == Some(name) && current.funcs.contains_key(&local) {
+            return Ok(target);
+        }
+
+        let module = modules.iter().find(|m| m.module.as_deref() == Some(name))
+            .ok_or_else(|| error(format!("Module {} not found for {}", name, target)))?;
+        if !module.funcs.contains_key(&local) {
+            return Err(error(format!("Function {} not found", target)));
+        }
+        if !module.exports.contains(&local) && current.module.as_deref() != Some(name) {
+            return Err(error(format!("Function {} is not exported", target)));
+        }
+        return Ok(target);
+    }
+
+    if current.funcs.contains_key(&target) {
+        return Ok(qualify(&current.module, &target));
+    }
+
+    if modules.iter().any(|m| m.module.is_none() && m.funcs.contains_key(&target)) {
+        return Ok(target);
+    }
+
+    Err(error(format!("Unresolved function {}", target)))
+}
+
+// Merges separately parsed modules into one program. Functions and labels of a `%module` are
+// qualified with its name, and `run` targets are resolved against the module's own functions,
+// the exports of other modules and the functions of unnamed modules.
+pub fn link(modules: Vec<ParserRet>) -> Result<ParserRet, Error> {
+    let linked = link_library(modules)?;
+    if !linked.funcs.contains_key("@entry") {
+        return Err(Error::new("No @entry function found".to_string(), 0, 0, &None));
+    }
+    Ok(linked)
+}
+
+// Links modules without requiring an `@entry`, for programs started at another function.
+pub fn link_library(modules: Vec<ParserRet>) -> Result<ParserRet, Error> {
+    for (i, module) in modules.iter().enumerate() {
+        if let Some(name) = &module.module {
+            if modules[..i].iter().any(|m| m.module.as_ref() == Some(name)) {
+                return Err(Error::new(format!("Module {} linked more than once", name), 0, 0, &None));
+            }
+        }
+    }
+
+    let mut instrs: Vec<Instruction> = Vec::new();
+    let mut labels: HashMap<String, usize> = HashMap::new();
+    let mut funcs: HashMap<String, usize> = HashMap::new();
+    let mut exports: Vec<String> = Vec::new();
+    let mut data: Vec<Instruction> = Vec::new();
+    let unnamed = modules.iter().filter(|m| m.module.is_none()).count();
+
+    for (i, module) in modules.iter().enumerate() {
+        let file = (unnamed > 1).then_some(i);
+        for instr in &module.instrs {
+            let params = match instr.kind {
+                InstructionKind::Run => vec![ValueType::String(resolve(&modules, module, instr)?)],
+                InstructionKind::Fun => vec![ValueType::String(qualify(&module.module, &instr.params[0].to_string()))],
+                InstructionKind::Lbl | InstructionKind::Jmp | InstructionKind::Jnz | InstructionKind::Jzr | InstructionKind::Try => {
+                    vec![ValueType::String(local_label(module, file, &instr.params[0].to_string()))]
+                },
+                _ => instr.params.clone(),
+            };
+
+            let name = params.first().map(|p| p.to_string()).unwrap_or_default();
+            let error = |message: String| Error::new(message, instr.line, instr.col, &None).in_file(&instr.file);
+            if instr.kind == InstructionKind::Fun && funcs.insert(name.clone(), instrs.len()).is_some() {
+                return Err(error(format!("Function {} defined in multiple modules", name)));
+            }
+            if instr.kind == InstructionKind::Lbl && labels.insert(name.clone(), instrs.len()).is_some() {
+                return Err(error(format!("Label {} defined in multiple modules", name)));
+            }
+
+            instrs.push(Instruction {
+                kind: instr.kind.clone(),
+                params,
+                line: instr.line,
+                col: instr.col,
+                file: instr.file.clone(),
+            });
+        }
+
+        exports.extend(module.exports.iter().map(|e| qualify(&module.module, e)));
+        for instr in &module.data {
+            if data.iter().any(|d| d.params[0] == instr.params[0]) {
+                let message = format!("Buffer {} defined in multiple modules", instr.params[0]);
+                return Err(Error::new(message, instr.line, instr.col, &None).in_file(&instr.file));
+            }
+            data.push(instr.clone());
+        }
+    }
+
+    Ok(ParserRet {
+        instrs,
+        labels,
+        funcs,
+        module: None,
+        exports,
+        data,
+    })
+}
@@ -0,0 +1,112 @@
+use std::fmt;
+use crate::parser::{Instruction, InstructionKind, ParserRet, ValueType};
+
+pub const LINTS: [&str; 5] = [
+    "unreachable-code",
+    "unused-label",
+    "unused-function",
+    "unused-variable",
+    "unfreed-buffer",
+];
+
+pub struct Warning {
+    pub lint: &'static str,
+    pub message: String,
+    pub file: Option<String>,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Warning {
+    fn new(lint: &'static str, message: String, instr: &Instruction) -> Self {
+        Self {
+            lint,
+            message,
+            file: instr.file.clone(),
+            line: instr.line,
+            col: instr.col,
+        }
+    }
+}
+
+impl fmt::Debug for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{} near {}:{}:{} [{}]", self.message, file, self.line, self.col, self.lint)
+        } else {
+            write!(f, "{} near {}:{} [{}]", self.message, self.line, self.col, self.lint)
+        }
+    }
+}
+
+fn unreachable_code(program: &ParserRet, warnings: &mut Vec<Warning>) {
+    let mut dead = false;
+    for instr in &program.instrs {
+        match instr.kind {
+            InstructionKind::Lbl | InstructionKind::Fun => dead = false,
+            InstructionKind::DebugSymbol => {},
+            _ if dead => {
+                warnings.push(Warning::new("unreachable-code", "Unreachable code after jmp, ret, thr or pnc".to_string(), instr));
+                dead = false;
+                continue;
+            },
+            InstructionKind::Jmp | InstructionKind::Ret | InstructionKind::Thr | InstructionKind::Pnc => dead = true,
+            _ => {},
+        }
+    }
+}
+
+// Reports every definition of `kind` whose name is never the parameter of one of `uses`.
+fn unused(program: &ParserRet, lint: &'static str, kind: InstructionKind, uses: &[InstructionKind], what: &str, warnings: &mut Vec<Warning>) {
+    let used: Vec<&ValueType> = program.instrs.iter()
+        .filter(|i| uses.contains(&i.kind))
+        .map(|i| &i.params[0])
+        .collect();
+
+    for instr in program.instrs.iter().filter(|i| i.kind == kind) {
+        let name = instr.params[0].to_string();
+        if used.contains(&&instr.params[0]) || name == "@entry" || program.exports.contains(&name) {
+            continue;
+        }
+        warnings.push(Warning::new(lint, format!("{} {} is never used", what, name), instr));
+    }
+}
+
+fn unused_variables(program: &ParserRet, warnings: &mut Vec<Warning>) {
+    let mut reported: Vec<&ValueType> = Vec::new();
+    for instr in program.instrs.iter().filter(|i| i.kind == InstructionKind::Pop) {
+        let var = &instr.params[0];
+        let name = var.to_string();
+        if name == "$" || name == "$_" || reported.contains(&var) {
+            continue;
+        }
+        if !program.instrs.iter().any(|i| i.kind == InstructionKind::Psh && &i.params[0] == var) {
+            warnings.push(Warning::new("unused-variable", format!("Variable {} is popped but never pushed", name), instr));
+            reported.push(var);
+        }
+    }
+}
+
+fn unfreed_buffers(program: &ParserRet, warnings: &mut Vec<Warning>) {
+    for instr in program.data.iter().chain(&program.instrs).filter(|i| i.kind == InstructionKind::Alc) {
+        let buffer = &instr.params[0];
+        if !program.instrs.iter().any(|i| i.kind == InstructionKind::Fre && &i.params[0] == buffer) {
+            warnings.push(Warning::new("unfreed-buffer", format!("Buffer {} is never freed", buffer), instr));
+        }
+    }
+}
+
+// Runs every lint not listed in `allowed` and returns the warnings in source order.
+pub fn lint(program: &ParserRet, allowed: &[String]) -> Vec<Warning> {
+    let mut warnings: Vec<Warning> = Vec::new();
+
+    unreachable_code(program, &mut warnings);
+    unused(program, "unused-label", InstructionKind::Lbl, &[InstructionKind::Jmp, InstructionKind::Jnz, InstructionKind::Jzr, InstructionKind::Try], "Label", &mut warnings);
+    unused(program, "unused-function", InstructionKind::Fun, &[InstructionKind::Run], "Function", &mut warnings);
+    unused_variables(program, &mut warnings);
+    unfreed_buffers(program, &mut warnings);
+
+    warnings.retain(|w| !allowed.iter().any(|a| a == w.lint));
+    warnings.sort_by(|a, b| (&a.file, a.line, a.col).cmp(&(&b.file, b.line, b.col)));
+    warnings
+}
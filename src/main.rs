@@ -1,80 +1,314 @@
-use crate::lexer::DebugSymbol;
-
-mod parser;
-mod lexer;
-mod evaluator;
-
-struct Error {
-    message: String,
-    path: Option<String>,
-    dsline: Option<usize>,
-    dscol: Option<usize>,
-    line: usize,
-    col: usize,
-}
-
-impl std::fmt::Debug for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        if let Some(path) = &self.path {
-            write!(f, "{} near {}:{}:{} ({}:{})", self.message, path, self.dsline.unwrap(), self.dscol.unwrap(), self.line, self.col)
-        } else {
-            write!(f, "{} near {}:{}", self.message, self.line, self.col)
-        }
-    }
-}
-
-impl Error {
-    fn new<S: Into<String>>(message: S, line: usize, col: usize, debug_symbol: &Option<DebugSymbol>) -> Self {
-        if let Some(ds) = debug_symbol {
-            Self {
-                message: message.into(),
-                path: Some(ds.clone().path),
-                dsline: Some(ds.line),
-                dscol: Some(ds.col),
-                line,
-                col,
-            }
-        } else {
-            Self {
-                message: message.into(),
-                path: None,
-                dsline: None,
-                dscol: None,
-                line,
-                col,
-            }
-        }
-    }
-}
-
-fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    let mut path: &String = &"test.zvm".to_string();
-    if args.len() >= 2 {
-        path = &args[1];
-    }
-    let code = std::fs::read_to_string(path).expect("Failed to read the file");
-
-    let tokens = lexer::lex(code).unwrap_or_else(|err| {
-        eprintln!("Runtime error: Failed to lex: {:?}", err);
-        std::process::exit(1);
-    });
-    let parsed = parser::parse(tokens).unwrap_or_else(|err| {
-        eprintln!("Runtime error: Failed to parse: {:?}", err);
-        std::process::exit(1);
-    });
-    let evaluated = evaluator::evaluate(parsed).unwrap_or_else(|err| {
-        eprintln!("Runtime error: Failed to evaluate: {:?}", err);
-        std::process::exit(1);
-    });
-
-    let stack = evaluated.0;
-    let code = evaluated.1;
-
-    println!("{:?}", stack);
-
-    std::process::exit(code);
-}
-
-#[cfg(test)]
-mod tests;
+use std::io::Read;
+use std::time::{Duration, Instant};
+use zelkel_vm::ErrorKind;
+use zelkel_vm::{coverage, debugger, disasm, evaluator, formatter, linker, lint, parser, preprocessor, profiler, repl, snapshot, tester, tracer};
+
+const USAGE: &str = "Usage: zelkel-vm <command> [options] <files...>
+
+Commands:
+  run [options] <files...>          link and run the files (also the default when no command is given)
+  trace [--json] <files...>         run while logging every instruction to stderr
+  debug [--entry @f] <files...>     run under the interactive debugger
+  test [--filter s] [--fuel n] [--timeout ms] <files...>
+                                    run every @test_* function, each in a fresh VM
+  check [--lib] <files...>          parse and link without running; --lib doesn't require an @entry
+  compile [-o out] <files...>       link the files into one canonical .zvm program
+  disasm <files...>                 print the parsed (or linked) program with instruction indices
+  fmt [--check] <files...>          reformat files in place
+  lint [--allow lint] <files...>    report likely mistakes
+  repl                              run instructions interactively
+
+Options for run and trace:
+  --print-stack                     print the final stack to stdout
+  --entry @function                 start at another function than @entry
+  --env                             let the program read environment variables
+  -- args...                        pass the remaining arguments to the program
+  --fuel n                          stop after n instructions
+  --timeout ms                      stop after ms milliseconds
+  --max-stack n, --max-calls n, --max-vars n, --max-buffer-bytes n, --max-string n, --max-items n, --max-depth n
+                                    override memory limits
+  --snapshot path                   save a snapshot when stopped by --fuel or --timeout
+  --resume path                     restore a snapshot before running
+  --profile, --profile-folded       print a profile to stderr
+  --coverage                        print an lcov coverage report to stderr
+
+A file named - is read from stdin.";
+
+fn fail(message: String) -> ! {
+    eprintln!("Runtime error: {}", message);
+    std::process::exit(1);
+}
+
+fn usage_error(message: String) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    std::process::exit(2);
+}
+
+fn read(path: &str) -> String {
+    if path == "-" {
+        let mut code = String::new();
+        std::io::stdin().read_to_string(&mut code).unwrap_or_else(|err| fail(format!("Failed to read stdin: {}", err)));
+        return code;
+    }
+    std::fs::read_to_string(path).unwrap_or_else(|err| fail(format!("Failed to read {}: {}", path, err)))
+}
+
+fn load(path: &str) -> parser::ParserRet {
+    let code = read(path);
+    let name = if path == "-" { "<stdin>" } else { path };
+
+    let tokens = preprocessor::preprocess(code, name).unwrap_or_else(|err| fail(format!("Failed to preprocess: {:?}", err)));
+    parser::parse_library(tokens).unwrap_or_else(|err| fail(format!("Failed to parse: {:?}", err)))
+}
+
+// Links the files without requiring an `@entry`; the entry function is checked when the VM starts.
+fn link(paths: &[String]) -> parser::ParserRet {
+    if paths.is_empty() {
+        usage_error("No input files".to_string());
+    }
+    let modules = paths.iter().map(|path| load(path)).collect();
+    linker::link_library(modules).unwrap_or_else(|err| fail(format!("Failed to link: {:?}", err)))
+}
+
+fn start(paths: &[String], entry: &str, limits: evaluator::Limits) -> evaluator::Vm {
+    evaluator::Vm::with_limits(link(paths), entry, limits).unwrap_or_else(|err| fail(format!("Failed to evaluate: {:?}", err)))
+}
+
+fn run(args: &[String], trace: bool) {
+    let mut mode: Option<String> = None;
+    let mut json = false;
+    let mut print_stack = false;
+    let mut fuel: Option<u64> = None;
+    let mut timeout: Option<u64> = None;
+    let mut limits = evaluator::Limits::default();
+    let mut save: Option<String> = None;
+    let mut resume: Option<String> = None;
+    let mut paths: Vec<String> = Vec::new();
+    let mut program_args: Vec<String> = Vec::new();
+    let mut env = false;
+    let mut entry = "@entry".to_string();
+
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--" => {
+                program_args = rest.by_ref().cloned().collect();
+            },
+            "--env" => env = true,
+            "--json" if trace => json = true,
+            "--print-stack" => print_stack = true,
+            "--profile" | "--profile-folded" | "--coverage" if !trace => mode = Some(arg.clone()),
+            "--snapshot" | "--resume" | "--entry" => {
+                let value = rest.next().cloned().unwrap_or_else(|| usage_error(format!("{} expects a value", arg)));
+                match arg.as_str() {
+                    "--snapshot" => save = Some(value),
+                    "--resume" => resume = Some(value),
+                    _ => entry = value,
+                }
+            },
+            "--fuel" | "--timeout" | "--max-stack" | "--max-calls" | "--max-vars" | "--max-buffer-bytes" | "--max-string" | "--max-items" | "--max-depth" => {
+                let value = rest.next().and_then(|v| v.parse::<u64>().ok())
+                    .unwrap_or_else(|| usage_error(format!("{} expects a number", arg)));
+                match arg.as_str() {
+                    "--fuel" => fuel = Some(value),
+                    "--timeout" => timeout = Some(value),
+                    "--max-stack" => limits.stack = value as usize,
+                    "--max-calls" => limits.ret_stack = value as usize,
+                    "--max-vars" => limits.vars = value as usize,
+                    "--max-buffer-bytes" => limits.buffer_bytes = value as usize,
+                    "--max-string" => limits.string_len = value as usize,
+                    "--max-items" => limits.items = value as usize,
+                    _ => limits.depth = value as usize,
+                }
+            },
+            option if option.starts_with('-') && option != "-" => usage_error(format!("Unknown option {}", option)),
+            _ => paths.push(arg.clone()),
+        }
+    }
+
+    let mut vm = start(&paths, &entry, limits);
+    if let Some(path) = &resume {
+        snapshot::restore(&mut vm, &read(path))
+            .unwrap_or_else(|err| fail(format!("Failed to resume: {:?}", err.in_file(&Some(path.clone())))));
+    }
+    vm.fuel = fuel;
+    vm.args = program_args;
+    if env {
+        vm.env = std::env::vars().collect();
+    }
+    vm.deadline = timeout.map(|ms| Instant::now() + Duration::from_millis(ms));
+
+    let code = match mode.as_deref() {
+        _ if trace => tracer::trace(&mut vm, json, &mut std::io::stderr().lock()),
+        Some("--coverage") => coverage::cover(&mut vm).map(|(code, coverage)| {
+            eprint!("{}", coverage::lcov(&vm.program, &coverage, &paths[0]));
+            code
+        }),
+        Some(_) => profiler::profile(&mut vm).map(|(code, profile)| {
+            if mode.as_deref() == Some("--profile-folded") {
+                eprint!("{}", profiler::folded(&profile));
+            } else {
+                eprint!("{}", profiler::report(&profile));
+            }
+            code
+        }),
+        None => vm.run(),
+    };
+    let code = code.unwrap_or_else(|err| {
+        if let Some(path) = save.as_ref().filter(|_| matches!(err.kind, ErrorKind::OutOfFuel | ErrorKind::Timeout)) {
+            std::fs::write(path, snapshot::save(&vm)).unwrap_or_else(|e| fail(format!("Failed to write {}: {}", path, e)));
+            eprintln!("Paused: {:?}, snapshot saved to {}", err, path);
+            std::process::exit(2);
+        }
+        fail(format!("Failed to evaluate: {:?}", err));
+    });
+
+    if print_stack {
+        println!("{:?}", vm.stack);
+    }
+
+    std::process::exit(code);
+}
+
+fn test(args: &[String]) {
+    let mut filter = String::new();
+    let mut fuel: Option<u64> = None;
+    let mut timeout: Option<u64> = None;
+    let mut paths: Vec<String> = Vec::new();
+
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--filter" => filter = rest.next().cloned().unwrap_or_else(|| usage_error("--filter expects a value".to_string())),
+            "--fuel" | "--timeout" => {
+                let value = rest.next().and_then(|v| v.parse::<u64>().ok())
+                    .unwrap_or_else(|| usage_error(format!("{} expects a number", arg)));
+                if arg == "--fuel" {
+                    fuel = Some(value);
+                } else {
+                    timeout = Some(value);
+                }
+            },
+            option if option.starts_with('-') && option != "-" => usage_error(format!("Unknown option {}", option)),
+            _ => paths.push(arg.clone()),
+        }
+    }
+
+    if paths.is_empty() {
+        usage_error("No input files".to_string());
+    }
+    let files: Vec<parser::ParserRet> = paths.iter().map(|path| load(path)).collect();
+    let results = tester::run_files(&files, &filter, |vm| {
+        vm.fuel = fuel;
+        vm.deadline = timeout.map(|ms| Instant::now() + Duration::from_millis(ms));
+    }).unwrap_or_else(|err| fail(format!("Failed to link: {:?}", err)));
+    let passed = tester::report(&results, &mut std::io::stdout()).unwrap_or_else(|err| fail(format!("Failed to write: {}", err)));
+    std::process::exit(if passed { 0 } else { 1 });
+}
+
+fn fmt(args: &[String]) {
+    let check = args.iter().any(|arg| arg == "--check");
+    let mut unformatted = false;
+
+    for path in args.iter().filter(|arg| *arg != "--check") {
+        let code = read(path);
+        let formatted = formatter::format(&code)
+            .unwrap_or_else(|err| fail(format!("Failed to format {}: {:?}", path, err.in_file(&Some(path.clone())))));
+
+        if path == "-" && !check {
+            print!("{}", formatted);
+            continue;
+        }
+        if formatted == code {
+            continue;
+        }
+        if check {
+            println!("Would reformat {}", path);
+            unformatted = true;
+        } else {
+            std::fs::write(path, formatted).unwrap_or_else(|err| fail(format!("Failed to write {}: {}", path, err)));
+        }
+    }
+
+    std::process::exit(if unformatted { 1 } else { 0 });
+}
+
+fn lint(args: &[String]) {
+    let mut allowed: Vec<String> = Vec::new();
+    let mut paths: Vec<String> = Vec::new();
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        if arg == "--allow" {
+            let name = rest.next().cloned().unwrap_or_default();
+            if !lint::LINTS.contains(&name.as_str()) {
+                fail(format!("Unknown lint '{}', expected one of: {}", name, lint::LINTS.join(", ")));
+            }
+            allowed.push(name);
+        } else {
+            paths.push(arg.clone());
+        }
+    }
+
+    for path in &paths {
+        for warning in lint::lint(&load(path), &allowed) {
+            println!("Warning: {:?}", warning);
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let Some(command) = args.get(1).map(|arg| arg.as_str()) else {
+        usage_error("No command given".to_string());
+    };
+    let rest = args.get(2..).unwrap_or(&[]);
+
+    match command {
+        "--help" | "-h" | "help" => println!("{}", USAGE),
+        "run" => run(rest, false),
+        "trace" => run(rest, true),
+        "test" => test(rest),
+        "check" => match rest {
+            [flag, paths @ ..] if flag == "--lib" => {
+                link(paths);
+            },
+            paths => {
+                start(paths, "@entry", evaluator::Limits::default());
+            },
+        },
+        "compile" => {
+            let (output, paths) = match rest {
+                [flag, output, paths @ ..] if flag == "-o" => (Some(output), paths),
+                paths => (None, paths),
+            };
+            let program = disasm::disasm(&link(paths));
+            match output {
+                Some(output) => std::fs::write(output, program).unwrap_or_else(|err| fail(format!("Failed to write {}: {}", output, err))),
+                None => print!("{}", program),
+            }
+        },
+        "disasm" => {
+            let mut modules: Vec<parser::ParserRet> = rest.iter().map(|path| load(path)).collect();
+            let program = match modules.len() {
+                0 => usage_error("No input files".to_string()),
+                1 => modules.pop().unwrap(),
+                _ => linker::link(modules).unwrap_or_else(|err| fail(format!("Failed to link: {:?}", err))),
+            };
+            print!("{}", disasm::disasm(&program));
+        },
+        "fmt" => fmt(rest),
+        "lint" => lint(rest),
+        "repl" => repl::repl(std::io::stdin().lock(), &mut std::io::stdout()).unwrap_or_else(|err| fail(format!("Failed to read stdin: {}", err))),
+        "debug" => {
+            let mut vm = match rest {
+                [flag, entry, paths @ ..] if flag == "--entry" => start(paths, entry, evaluator::Limits::default()),
+                paths => start(paths, "@entry", evaluator::Limits::default()),
+            };
+            let code = debugger::debug(&mut vm, std::io::stdin().lock(), &mut std::io::stdout())
+                .unwrap_or_else(|err| fail(format!("Failed to read stdin: {}", err)));
+            std::process::exit(code.unwrap_or(0));
+        },
+        option if option.starts_with('-') && option != "-" => usage_error(format!("Unknown option {}", option)),
+        _ => run(&args[1..], false),
+    }
+}
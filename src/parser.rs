@@ -1,396 +1,657 @@
-use std::collections::HashMap;
-use std::fmt;
-use crate::Error;
-use crate::lexer::{DebugSymbol, Token, TokenValue};
-
-#[derive(Debug, PartialEq, Clone)]
-pub enum ValueType {
-    Integer(i32),
-    Float(f32),
-    String(String),
-    Boolean(bool),
-    Buffer(String),
-    Variable(String),
-    DebugSymbol(DebugSymbol),
-}
-
-impl fmt::Display for ValueType {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            ValueType::Integer(i) => write!(f, "{}", i),
-            ValueType::Float(fl) => write!(f, "{}", fl),
-            ValueType::String(s) => write!(f, "{}", s),
-            ValueType::Boolean(b) => write!(f, "{}", b),
-            ValueType::Buffer(b) => write!(f, "{}", b),
-            ValueType::Variable(v) => write!(f, "{}", v),
-            ValueType::DebugSymbol(ds) => write!(f, "{}:{}:{}", ds.path, ds.line, ds.col),
-        }
-    }
-}
-
-impl ValueType {
-    pub fn to_int(&self) -> Result<i32, String> {
-        match self {
-            ValueType::Integer(i) => Ok(*i),
-            ValueType::Float(f) => Ok(*f as i32),
-            ValueType::String(s) => s.parse::<i32>().map_err(|_| "Cannot convert string to int".to_string()),
-            ValueType::Boolean(b) => Ok(*b as i32),
-            ValueType::Buffer(_) => Err("Cannot convert buffer to int".to_string()),
-            ValueType::Variable(_) => Err("Cannot convert variable to int".to_string()),
-            ValueType::DebugSymbol(_) => Err("Cannot convert debug symbol to int".to_string()),
-        }
-    }
-
-    pub fn as_debug_symbol(&self) -> Result<DebugSymbol, String> {
-        match self {
-            ValueType::DebugSymbol(ds) => Ok(ds.clone()),
-            _ => Err("Expected debug symbol".to_owned()),
-        }
-    }
-}
-
-#[derive(Debug, PartialEq)]
-pub enum InstructionKind {
-    Add,
-    Sub,
-    Mul,
-    Div,
-    Mod,
-    Cmp,
-    Dup,
-    Pop,
-    Psh,
-    Rot,
-    Jmp,
-    Jnz,
-    Jzr,
-    Type,
-    Ret,
-    Run,
-    Sys,
-    Len,
-    Lbl,
-    Fun,
-    Fre,
-    Alc,
-    DebugSymbol,
-}
-
-#[derive(Debug, PartialEq)]
-pub struct Instruction {
-    pub kind: InstructionKind,
-    pub params: Vec<ValueType>,
-    pub line: usize,
-    pub col: usize,
-}
-
-#[derive(Debug)]
-pub struct ParserRet {
-    pub instrs: Vec<Instruction>,
-    pub labels: HashMap<String, usize>,
-    pub funcs: HashMap<String, usize>,
-}
-
-fn current(tokens: &Vec<Token>, i: usize) -> Option<&Token> {
-    if i < tokens.len() {
-        Some(&tokens[i])
-    } else {
-        None
-    }
-}
-
-fn next(tokens: &Vec<Token>, i: usize) -> Option<&Token> {
-    let tok = current(tokens, i + 1);
-    Some(tok?)
-}
-
-fn expect<'a>(tokens: &'a Vec<Token>, i: usize, kind: &str) -> Result<&'a Token, Error> {
-    let t = current(tokens, i).ok_or(
-        Error::new(format!("Unexpected end of input while expecting token of kind '{}'", kind), tokens.last().unwrap().line, tokens.last().unwrap().col, &None)
-    )?;
-    if t.kind == kind {
-        Ok(t)
-    } else {
-        Err(Error::new(format!("Expected token of kind '{}', got '{:?}'", kind, t), t.line, t.col, &None))
-    }
-}
-
-pub fn parse(tokens: Vec<Token>) -> Result<ParserRet, Error> {
-    let mut instrs: Vec<Instruction> = Vec::new();
-    let mut i = 0;
-
-    let mut labels: HashMap<String, usize> = HashMap::new();
-    let mut funcs: HashMap<String, usize> = HashMap::new();
-    let mut bufs: Vec<String> = Vec::new();
-    let mut vars: Vec<String> = Vec::new();
-
-    while i < tokens.len() {
-        let t = current(&tokens, i).unwrap();
-
-        match t.kind {
-            "identifier" => {
-                if t.value == TokenValue::Identifier("psh".to_string()) {
-                    let next_token = next(&tokens, i).unwrap();
-                    let value = &next_token.value;
-                    let value = match value {
-                        TokenValue::Integer(i) => ValueType::Integer(*i),
-                        TokenValue::Float(f) => ValueType::Float(*f),
-                        TokenValue::String(s) => ValueType::String(s.clone()),
-                        TokenValue::Identifier(s) if s == "true" => ValueType::Boolean(true),
-                        TokenValue::Identifier(s) if s == "false" => ValueType::Boolean(false),
-                        TokenValue::Buffer(s) => {
-                            if bufs.iter().find(|&b| b == s).is_none() {
-                                return Err(Error::new(format!("Buffer {} not found", s), t.line, t.col, &None));
-                            }
-                            ValueType::Buffer(s.to_string())
-                        },
-                        TokenValue::Variable(s) => {
-                            if vars.iter().find(|&b| b == s).is_none() {
-                                return Err(Error::new(format!("Variable {} not found", s), t.line, t.col, &None));
-                            }
-                            ValueType::Variable(s.to_string())
-                        },
-                        _ => {
-                            return Err(Error::new(format!("Invalid value for psh: {:?}", value), t.line, t.col, &None));
-                        }
-                    };
-
-                    i += 2;
-
-                    let instruction = Instruction {
-                        kind: InstructionKind::Psh,
-                        params: vec![value],
-                        line: t.line,
-                        col: t.col,
-                    };
-
-                    instrs.push(instruction);
-                } else if t.value == TokenValue::Identifier("jmp".to_string()) {
-                    i += 1;
-                    let label = expect(&tokens, i, "label")?.value.to_string();
-                    i += 1;
-
-                    let instruction = Instruction {
-                        kind: InstructionKind::Jmp,
-                        params: vec![ValueType::String(label.clone())],
-                        line: t.line,
-                        col: t.col,
-                    };
-
-                    instrs.push(instruction);
-                } else if t.value == TokenValue::Identifier("jnz".to_string()) {
-                    i += 1;
-                    let label = expect(&tokens, i, "label")?.value.to_string();
-                    i += 1;
-
-                    let instruction = Instruction {
-                        kind: InstructionKind::Jnz,
-                        params: vec![ValueType::String(label.clone())],
-                        line: t.line,
-                        col: t.col,
-                    };
-
-                    instrs.push(instruction);
-                } else if t.value == TokenValue::Identifier("jzr".to_string()) {
-                    i += 1;
-                    let label = expect(&tokens, i, "label")?.value.to_string();
-                    i += 1;
-
-                    let instruction = Instruction {
-                        kind: InstructionKind::Jzr,
-                        params: vec![ValueType::String(label.clone())],
-                        line: t.line,
-                        col: t.col,
-                    };
-
-                    instrs.push(instruction);
-                } else if t.value == TokenValue::Identifier("typ".to_string()) {
-                    i += 1;
-                    let ident = expect(&tokens, i, "identifier")?.value.to_string();
-                    i += 1;
-
-                    let instruction = Instruction {
-                        kind: InstructionKind::Type,
-                        params: vec![ValueType::String(ident.clone())],
-                        line: t.line,
-                        col: t.col,
-                    };
-
-                    instrs.push(instruction);
-                } else if t.value == TokenValue::Identifier("run".to_string()) {
-                    i += 1;
-                    let func = expect(&tokens, i, "function")?.value.to_string();
-                    i += 1;
-
-                    let instruction = Instruction {
-                        kind: InstructionKind::Run,
-                        params: vec![ValueType::String(func.clone())],
-                        line: t.line,
-                        col: t.col,
-                    };
-
-                    instrs.push(instruction);
-                } else if t.value == TokenValue::Identifier("alc".to_string()) {
-                    i += 1;
-                    let buffer_name = expect(&tokens, i, "buffer")?.value.to_string();
-                    if bufs.iter().find(|b| &b == &&&buffer_name).is_some() {
-                        return Err(Error::new(format!("Buffer {} already exists", buffer_name), t.line, t.col, &None));
-                    }
-
-                    i += 1;
-                    expect(&tokens, i, "punctuation")?;
-                    i += 1;
-                    let buffer_size = expect(&tokens, i, "integer")?.value.to_string().parse().unwrap();
-                    i += 1;
-
-                    bufs.push(buffer_name.clone());
-
-                    let instruction = Instruction {
-                        kind: InstructionKind::Alc,
-                        params: vec![ValueType::Buffer(buffer_name.clone()), ValueType::Integer(buffer_size)],
-                        line: t.line,
-                        col: t.col,
-                    };
-
-                    instrs.push(instruction);
-                } else if t.value == TokenValue::Identifier("pop".to_string()) {
-                    i += 1;
-                    let var_name = expect(&tokens, i, "variable")?.value.to_string();
-
-                    i += 1;
-                    if vars.iter().find(|&b| b == &var_name).is_none() {
-                        if var_name != "$_" && var_name != "$" {
-                            vars.push(var_name.clone());
-                        }
-                    }
-
-                    let instruction = Instruction {
-                        kind: InstructionKind::Pop,
-                        params: vec![ValueType::Variable(var_name.clone())],
-                        line: t.line,
-                        col: t.col,
-                    };
-
-                    instrs.push(instruction);
-                } else if t.value == TokenValue::Identifier("dlc".to_string()) {
-                    let next_token = next(&tokens, i).unwrap();
-                    let value = match next_token.kind.to_string().as_str() {
-                        "variable" => {
-                            let var_name = next_token.value.to_string();
-                            if vars.iter().find(|&b| b == &var_name).is_none() {
-                                return Err(Error::new(format!("Variable {} not found", var_name), t.line, t.col, &None));
-                            }
-                            vars.remove(vars.iter().position(|x| x == &var_name).unwrap());
-                            ValueType::Variable(var_name)
-                        },
-                        "buffer" => {
-                            let buffer_name = next_token.value.to_string();
-                            if bufs.iter().find(|&b| b == &buffer_name).is_none() {
-                                return Err(Error::new(format!("Buffer {} not found", buffer_name), t.line, t.col, &None));
-                            }
-                            bufs.remove(bufs.iter().position(|x| x == &buffer_name).unwrap());
-                            ValueType::Buffer(buffer_name)
-                        },
-                        _ => return Err(Error::new("Expected variable or buffer".to_string(), t.line, t.col, &None)),
-                    };
-
-                    i += 2;
-                    let instruction = Instruction {
-                        kind: InstructionKind::Fre,
-                        params: vec![value],
-                        line: t.line,
-                        col: t.col,
-                    };
-
-                    instrs.push(instruction);
-                } else {
-                    let kind = match t.value {
-                        TokenValue::Identifier(ref s) if s == "add" => InstructionKind::Add,
-                        TokenValue::Identifier(ref s) if s == "sub" => InstructionKind::Sub,
-                        TokenValue::Identifier(ref s) if s == "mul" => InstructionKind::Mul,
-                        TokenValue::Identifier(ref s) if s == "div" => InstructionKind::Div,
-                        TokenValue::Identifier(ref s) if s == "mod" => InstructionKind::Mod,
-                        TokenValue::Identifier(ref s) if s == "cmp" => InstructionKind::Cmp,
-                        TokenValue::Identifier(ref s) if s == "dup" => InstructionKind::Dup,
-                        TokenValue::Identifier(ref s) if s == "rot" => InstructionKind::Rot,
-                        TokenValue::Identifier(ref s) if s == "ret" => InstructionKind::Ret,
-                        TokenValue::Identifier(ref s) if s == "sys" => InstructionKind::Sys,
-                        TokenValue::Identifier(ref s) if s == "len" => InstructionKind::Len,
-                        _ => return Err(Error::new(format!("Invalid instruction: {:?}", t), t.line, t.col, &None)),
-                    };
-
-                    i += 1;
-
-                    let instruction = Instruction {
-                        kind,
-                        params: vec![],
-                        line: t.line,
-                        col: t.col,
-                    };
-
-                    instrs.push(instruction);
-                }
-            },
-            "label" => {
-                i += 1;
-                if expect(&tokens, i,"punctuation")?.value.to_string() != ":" {
-                    return Err(Error::new("Expected ':' after label".to_string(), t.line, t.col, &None));
-                }
-                i += 1;
-
-                if labels.get(&t.value.to_string()).is_some() {
-                    return Err(Error::new(format!("Label {} already exists", t.value.to_string()), t.line, t.col, &None));
-                }
-
-                labels.insert(t.value.to_string(), instrs.len());
-                instrs.push(Instruction {
-                    kind: InstructionKind::Lbl,
-                    params: vec![ValueType::String(t.value.to_string())],
-                    line: t.line,
-                    col: t.col,
-                });
-            },
-            "function" => {
-                i += 1;
-                if expect(&tokens, i,"punctuation")?.value.to_string() != ":" {
-                    return Err(Error::new("Expected ':' after function".to_string(), t.line, t.col, &None));
-                }
-                i += 1;
-
-                if funcs.get(&t.value.to_string()).is_some() {
-                    return Err(Error::new(format!("Function {} already exists", t.value.to_string()), t.line, t.col, &None));
-                }
-
-                funcs.insert(t.value.to_string(), instrs.len());
-                instrs.push(Instruction {
-                    kind: InstructionKind::Fun,
-                    params: vec![ValueType::String(t.value.to_string())],
-                    line: t.line,
-                    col: t.col,
-                });
-            },
-            "debugsymbol" => {
-                i += 1;
-                instrs.push(Instruction {
-                    kind: InstructionKind::DebugSymbol,
-                    params: vec![ValueType::DebugSymbol(t.value.as_debug_symbol().unwrap())],
-                    line: t.line,
-                    col: t.col,
-                })
-            }
-            _ => {
-                Err(Error::new(format!("Unexpected token: {:?}", t), t.line, t.col, &None))?;
-            }
-        }
-    }
-
-    if funcs.get("@entry").is_none() {
-        return Err(Error::new("No @entry function found".to_string(), 0, 0, &None));
-    }
-
-    Ok(ParserRet {
-        instrs,
-        labels,
-        funcs,
-    })
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::rc::Rc;
+use crate::Error;
+use crate::lexer::{DebugSymbol, Token, TokenValue};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ValueType {
+    Integer(i32),
+    Float(f32),
+    String(String),
+    Boolean(bool),
+    Buffer(String),
+    Variable(String),
+    DebugSymbol(DebugSymbol),
+    // Shared between copies, so changes made through one are seen by all of them.
+    List(Rc<RefCell<Vec<ValueType>>>),
+    // Shared like lists. Ordered, so keys come out sorted.
+    Map(Rc<RefCell<BTreeMap<MapKey, ValueType>>>),
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub enum MapKey {
+    Integer(i32),
+    String(String),
+}
+
+impl MapKey {
+    pub fn new(value: &ValueType) -> Result<Self, String> {
+        match value {
+            ValueType::Integer(i) => Ok(MapKey::Integer(*i)),
+            ValueType::String(s) => Ok(MapKey::String(s.clone())),
+            _ => Err("Invalid key type".to_string()),
+        }
+    }
+
+    pub fn to_value(&self) -> ValueType {
+        match self {
+            MapKey::Integer(i) => ValueType::Integer(*i),
+            MapKey::String(s) => ValueType::String(s.clone()),
+        }
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValueType::Integer(i) => write!(f, "{}", i),
+            ValueType::Float(fl) => write!(f, "{}", fl),
+            ValueType::String(s) => write!(f, "{}", s),
+            ValueType::Boolean(b) => write!(f, "{}", b),
+            ValueType::Buffer(b) => write!(f, "{}", b),
+            ValueType::Variable(v) => write!(f, "{}", v),
+            ValueType::DebugSymbol(ds) => write!(f, "{}:{}:{}", ds.path, ds.line, ds.col),
+            ValueType::List(l) => {
+                let items: Vec<String> = l.borrow().iter().map(crate::disasm::literal).collect();
+                write!(f, "[{}]", items.join(", "))
+            },
+            ValueType::Map(m) => {
+                let entries: Vec<String> = m.borrow().iter()
+                    .map(|(k, v)| format!("{}: {}", crate::disasm::literal(&k.to_value()), crate::disasm::literal(v)))
+                    .collect();
+                write!(f, "{{{}}}", entries.join(", "))
+            },
+        }
+    }
+}
+
+impl ValueType {
+    pub fn to_int(&self) -> Result<i32, String> {
+        match self {
+            ValueType::Integer(i) => Ok(*i),
+            ValueType::Float(f) => Ok(*f as i32),
+            ValueType::String(s) => s.parse::<i32>().map_err(|_| "Cannot convert string to int".to_string()),
+            ValueType::Boolean(b) => Ok(*b as i32),
+            ValueType::Buffer(_) => Err("Cannot convert buffer to int".to_string()),
+            ValueType::Variable(_) => Err("Cannot convert variable to int".to_string()),
+            ValueType::DebugSymbol(_) => Err("Cannot convert debug symbol to int".to_string()),
+            ValueType::List(_) => Err("Cannot convert list to int".to_string()),
+            ValueType::Map(_) => Err("Cannot convert map to int".to_string()),
+        }
+    }
+
+    // Truthiness as tested by `jnz` and `jzr`.
+    pub fn to_bool(&self) -> Result<bool, String> {
+        match self {
+            ValueType::Integer(i) => Ok(*i != 0),
+            ValueType::Float(f) => Ok(*f != 0.0),
+            ValueType::String(s) => Ok(!s.is_empty()),
+            ValueType::Boolean(b) => Ok(*b),
+            _ => Err("Invalid type".to_string()),
+        }
+    }
+
+    pub fn as_debug_symbol(&self) -> Result<DebugSymbol, String> {
+        match self {
+            ValueType::DebugSymbol(ds) => Ok(ds.clone()),
+            _ => Err("Expected debug symbol".to_owned()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum InstructionKind {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Cmp,
+    Dup,
+    Pop,
+    Psh,
+    Rot,
+    Jmp,
+    Jnz,
+    Jzr,
+    Type,
+    Ret,
+    Run,
+    Sys,
+    Len,
+    Ern,
+    Lnw,
+    Lpu,
+    Lpo,
+    Lgt,
+    Lst,
+    Lsl,
+    Mnw,
+    Mst,
+    Mgt,
+    Mrm,
+    Mhs,
+    Mks,
+    Lbl,
+    Fun,
+    Fre,
+    Alc,
+    Ast,
+    Pnc,
+    Try,
+    End,
+    Thr,
+    Agc,
+    Arg,
+    Env,
+    DebugSymbol,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Instruction {
+    pub kind: InstructionKind,
+    pub params: Vec<ValueType>,
+    pub line: usize,
+    pub col: usize,
+    pub file: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ParserRet {
+    pub instrs: Vec<Instruction>,
+    pub labels: HashMap<String, usize>,
+    pub funcs: HashMap<String, usize>,
+    pub module: Option<String>,
+    pub exports: Vec<String>,
+    pub data: Vec<Instruction>,
+}
+
+// Programs compare by their instructions and symbols; source positions are ignored.
+impl PartialEq for ParserRet {
+    fn eq(&self, other: &Self) -> bool {
+        let same = |a: &[Instruction], b: &[Instruction]| {
+            a.len() == b.len() && a.iter().zip(b).all(|(x, y)| x.kind == y.kind && x.params == y.params)
+        };
+
+        same(&self.instrs, &other.instrs)
+            && same(&self.data, &other.data)
+            && self.labels == other.labels
+            && self.funcs == other.funcs
+            && self.module == other.module
+            && self.exports == other.exports
+    }
+}
+
+fn current(tokens: &[Token], i: usize) -> Option<&Token> {
+    if i < tokens.len() {
+        Some(&tokens[i])
+    } else {
+        None
+    }
+}
+
+fn next(tokens: &[Token], i: usize) -> Option<&Token> {
+    let tok = current(tokens, i + 1);
+    tok
+}
+
+fn expect<'a>(tokens: &'a [Token], i: usize, kind: &str) -> Result<&'a Token, Error> {
+    let t = current(tokens, i).ok_or(
+        Error::new(format!("Unexpected end of input while expecting token of kind '{}'", kind), tokens.last().unwrap().line, tokens.last().unwrap().col, &None).in_file(&tokens.last().unwrap().file)
+    )?;
+    if t.kind == kind {
+        Ok(t)
+    } else {
+        Err(Error::new(format!("Expected token of kind '{}', got '{:?}'", kind, t), t.line, t.col, &None).in_file(&t.file))
+    }
+}
+
+pub fn literal(t: &Token, consts: &HashMap<String, ValueType>) -> Result<ValueType, Error> {
+    match &t.value {
+        TokenValue::Integer(i) => Ok(ValueType::Integer(*i)),
+        TokenValue::Float(f) => Ok(ValueType::Float(*f)),
+        TokenValue::String(s) => Ok(ValueType::String(s.clone())),
+        TokenValue::Identifier(s) if s == "true" => Ok(ValueType::Boolean(true)),
+        TokenValue::Identifier(s) if s == "false" => Ok(ValueType::Boolean(false)),
+        TokenValue::Identifier(s) => consts.get(s).cloned()
+            .ok_or(Error::new(format!("Constant {} not found", s), t.line, t.col, &None).in_file(&t.file)),
+        _ => Err(Error::new(format!("Expected a literal or constant, got {:?}", t.value), t.line, t.col, &None).in_file(&t.file)),
+    }
+}
+
+// `.const` and `.data` lex as labels; they are only section keywords when not followed by ':'.
+fn keyword(tokens: &[Token], i: usize, name: &str) -> bool {
+    tokens[i].value == TokenValue::Label(name.to_string())
+        && next(tokens, i).is_none_or(|t| t.value != TokenValue::Punctuation(':'))
+}
+
+pub fn parse(tokens: Vec<Token>) -> Result<ParserRet, Error> {
+    let parsed = parse_library(tokens)?;
+    if parsed.module.is_none() && !parsed.funcs.contains_key("@entry") {
+        return Err(Error::new("No @entry function found".to_string(), 0, 0, &None));
+    }
+    Ok(parsed)
+}
+
+// The buffers, variables and constants declared so far, which later instructions may refer to.
+#[derive(Debug, Clone, Default)]
+pub struct Scope {
+    pub bufs: Vec<String>,
+    pub vars: Vec<String>,
+    pub consts: HashMap<String, ValueType>,
+}
+
+// Parses a file that is only linked into other programs, so it doesn't need an `@entry`.
+pub fn parse_library(tokens: Vec<Token>) -> Result<ParserRet, Error> {
+    parse_in_scope(tokens, &mut Scope::default())
+}
+
+// Like `parse_library`, but for a piece of a larger program, such as a line entered into the REPL:
+// names declared by earlier pieces are taken from `scope`, which is updated with the new ones.
+pub fn parse_in_scope(tokens: Vec<Token>, scope: &mut Scope) -> Result<ParserRet, Error> {
+    let mut instrs: Vec<Instruction> = Vec::new();
+    let mut i = 0;
+
+    let mut labels: HashMap<String, usize> = HashMap::new();
+    let mut funcs: HashMap<String, usize> = HashMap::new();
+    let Scope { bufs, vars, consts } = scope;
+    let mut module: Option<String> = None;
+    let mut exports: Vec<(String, &Token)> = Vec::new();
+    let mut data: Vec<Instruction> = Vec::new();
+    let mut in_data = false;
+
+    while i < tokens.len() {
+        let t = current(&tokens, i).unwrap();
+
+        match t.kind {
+            "identifier" if in_data && t.value != TokenValue::Identifier("alc".to_string()) => {
+                return Err(Error::new(format!("Only alc is allowed in the .data section, got {}", t.value), t.line, t.col, &None).in_file(&t.file));
+            },
+            "identifier" => {
+                if t.value == TokenValue::Identifier("psh".to_string()) {
+                    let next_token = next(&tokens, i).unwrap();
+                    let value = &next_token.value;
+                    let value = match value {
+                        TokenValue::Integer(i) => ValueType::Integer(*i),
+                        TokenValue::Float(f) => ValueType::Float(*f),
+                        TokenValue::String(s) => ValueType::String(s.clone()),
+                        TokenValue::Identifier(_) => literal(next_token, consts)?,
+                        TokenValue::Buffer(s) => {
+                            if bufs.iter().find(|&b| b == s).is_none() {
+                                return Err(Error::new(format!("Buffer {} not found", s), t.line, t.col, &None).in_file(&t.file));
+                            }
+                            ValueType::Buffer(s.to_string())
+                        },
+                        TokenValue::Variable(s) => {
+                            if vars.iter().find(|&b| b == s).is_none() {
+                                return Err(Error::new(format!("Variable {} not found", s), t.line, t.col, &None).in_file(&t.file));
+                            }
+                            ValueType::Variable(s.to_string())
+                        },
+                        _ => {
+                            return Err(Error::new(format!("Invalid value for psh: {:?}", value), t.line, t.col, &None).in_file(&t.file));
+                        }
+                    };
+
+                    i += 2;
+
+                    let instruction = Instruction {
+                        kind: InstructionKind::Psh,
+                        params: vec![value],
+                        line: t.line,
+                        col: t.col,
+                        file: t.file.clone(),
+                    };
+
+                    instrs.push(instruction);
+                } else if t.value == TokenValue::Identifier("jmp".to_string()) {
+                    i += 1;
+                    let label = expect(&tokens, i, "label")?.value.to_string();
+                    i += 1;
+
+                    let instruction = Instruction {
+                        kind: InstructionKind::Jmp,
+                        params: vec![ValueType::String(label.clone())],
+                        line: t.line,
+                        col: t.col,
+                        file: t.file.clone(),
+                    };
+
+                    instrs.push(instruction);
+                } else if t.value == TokenValue::Identifier("jnz".to_string()) {
+                    i += 1;
+                    let label = expect(&tokens, i, "label")?.value.to_string();
+                    i += 1;
+
+                    let instruction = Instruction {
+                        kind: InstructionKind::Jnz,
+                        params: vec![ValueType::String(label.clone())],
+                        line: t.line,
+                        col: t.col,
+                        file: t.file.clone(),
+                    };
+
+                    instrs.push(instruction);
+                } else if t.value == TokenValue::Identifier("jzr".to_string()) {
+                    i += 1;
+                    let label = expect(&tokens, i, "label")?.value.to_string();
+                    i += 1;
+
+                    let instruction = Instruction {
+                        kind: InstructionKind::Jzr,
+                        params: vec![ValueType::String(label.clone())],
+                        line: t.line,
+                        col: t.col,
+                        file: t.file.clone(),
+                    };
+
+                    instrs.push(instruction);
+                } else if t.value == TokenValue::Identifier("try".to_string()) {
+                    i += 1;
+                    let label = expect(&tokens, i, "label")?.value.to_string();
+                    i += 1;
+
+                    let instruction = Instruction {
+                        kind: InstructionKind::Try,
+                        params: vec![ValueType::String(label.clone())],
+                        line: t.line,
+                        col: t.col,
+                        file: t.file.clone(),
+                    };
+
+                    instrs.push(instruction);
+                } else if t.value == TokenValue::Identifier("typ".to_string()) {
+                    i += 1;
+                    let ident = expect(&tokens, i, "identifier")?.value.to_string();
+                    i += 1;
+
+                    let instruction = Instruction {
+                        kind: InstructionKind::Type,
+                        params: vec![ValueType::String(ident.clone())],
+                        line: t.line,
+                        col: t.col,
+                        file: t.file.clone(),
+                    };
+
+                    instrs.push(instruction);
+                } else if t.value == TokenValue::Identifier("run".to_string()) {
+                    i += 1;
+                    let func = expect(&tokens, i, "function")?.value.to_string();
+                    i += 1;
+
+                    let instruction = Instruction {
+                        kind: InstructionKind::Run,
+                        params: vec![ValueType::String(func.clone())],
+                        line: t.line,
+                        col: t.col,
+                        file: t.file.clone(),
+                    };
+
+                    instrs.push(instruction);
+                } else if t.value == TokenValue::Identifier("alc".to_string()) {
+                    i += 1;
+                    let buffer_name = expect(&tokens, i, "buffer")?.value.to_string();
+                    if bufs.iter().find(|b| b == &&buffer_name).is_some() {
+                        return Err(Error::new(format!("Buffer {} already exists", buffer_name), t.line, t.col, &None).in_file(&t.file));
+                    }
+
+                    i += 1;
+                    expect(&tokens, i, "punctuation")?;
+                    i += 1;
+                    let size_token = current(&tokens, i).ok_or(Error::new("Expected buffer size or initializer", t.line, t.col, &None).in_file(&t.file))?;
+                    let buffer_size = match literal(size_token, consts)? {
+                        value @ (ValueType::Integer(_) | ValueType::String(_)) => value,
+                        value => return Err(Error::new(format!("Invalid buffer size or initializer: {:?}", value), t.line, t.col, &None).in_file(&t.file)),
+                    };
+                    i += 1;
+
+                    bufs.push(buffer_name.clone());
+
+                    let instruction = Instruction {
+                        kind: InstructionKind::Alc,
+                        params: vec![ValueType::Buffer(buffer_name.clone()), buffer_size],
+                        line: t.line,
+                        col: t.col,
+                        file: t.file.clone(),
+                    };
+
+                    if in_data {
+                        data.push(instruction);
+                    } else {
+                        instrs.push(instruction);
+                    }
+                } else if t.value == TokenValue::Identifier("pop".to_string()) {
+                    i += 1;
+                    let var_name = expect(&tokens, i, "variable")?.value.to_string();
+
+                    i += 1;
+                    if !vars.contains(&var_name) && var_name != "$_" && var_name != "$" {
+                        vars.push(var_name.clone());
+                    }
+
+                    let instruction = Instruction {
+                        kind: InstructionKind::Pop,
+                        params: vec![ValueType::Variable(var_name.clone())],
+                        line: t.line,
+                        col: t.col,
+                        file: t.file.clone(),
+                    };
+
+                    instrs.push(instruction);
+                } else if t.value == TokenValue::Identifier("ast".to_string()) || t.value == TokenValue::Identifier("pnc".to_string()) {
+                    let (kind, message) = if t.value == TokenValue::Identifier("pnc".to_string()) {
+                        i += 1;
+                        (InstructionKind::Pnc, Some(expect(&tokens, i, "string")?.value.to_string()))
+                    } else {
+                        // The message of `ast` is optional, so it has to be on the same line.
+                        match next(&tokens, i) {
+                            Some(message) if message.kind == "string" && message.line == t.line => {
+                                i += 1;
+                                (InstructionKind::Ast, Some(message.value.to_string()))
+                            },
+                            _ => (InstructionKind::Ast, None),
+                        }
+                    };
+                    i += 1;
+
+                    let instruction = Instruction {
+                        kind,
+                        params: message.into_iter().map(ValueType::String).collect(),
+                        line: t.line,
+                        col: t.col,
+                        file: t.file.clone(),
+                    };
+
+                    instrs.push(instruction);
+                } else if t.value == TokenValue::Identifier("dlc".to_string()) {
+                    let next_token = next(&tokens, i).unwrap();
+                    let value = match next_token.kind.to_string().as_str() {
+                        "variable" => {
+                            let var_name = next_token.value.to_string();
+                            if vars.iter().find(|&b| b == &var_name).is_none() {
+                                return Err(Error::new(format!("Variable {} not found", var_name), t.line, t.col, &None).in_file(&t.file));
+                            }
+                            vars.remove(vars.iter().position(|x| x == &var_name).unwrap());
+                            ValueType::Variable(var_name)
+                        },
+                        "buffer" => {
+                            let buffer_name = next_token.value.to_string();
+                            if bufs.iter().find(|&b| b == &buffer_name).is_none() {
+                                return Err(Error::new(format!("Buffer {} not found", buffer_name), t.line, t.col, &None).in_file(&t.file));
+                            }
+                            bufs.remove(bufs.iter().position(|x| x == &buffer_name).unwrap());
+                            ValueType::Buffer(buffer_name)
+                        },
+                        _ => return Err(Error::new("Expected variable or buffer".to_string(), t.line, t.col, &None).in_file(&t.file)),
+                    };
+
+                    i += 2;
+                    let instruction = Instruction {
+                        kind: InstructionKind::Fre,
+                        params: vec![value],
+                        line: t.line,
+                        col: t.col,
+                        file: t.file.clone(),
+                    };
+
+                    instrs.push(instruction);
+                } else {
+                    let kind = match t.value {
+                        TokenValue::Identifier(ref s) if s == "add" => InstructionKind::Add,
+                        TokenValue::Identifier(ref s) if s == "sub" => InstructionKind::Sub,
+                        TokenValue::Identifier(ref s) if s == "mul" => InstructionKind::Mul,
+                        TokenValue::Identifier(ref s) if s == "div" => InstructionKind::Div,
+                        TokenValue::Identifier(ref s) if s == "mod" => InstructionKind::Mod,
+                        TokenValue::Identifier(ref s) if s == "cmp" => InstructionKind::Cmp,
+                        TokenValue::Identifier(ref s) if s == "dup" => InstructionKind::Dup,
+                        TokenValue::Identifier(ref s) if s == "rot" => InstructionKind::Rot,
+                        TokenValue::Identifier(ref s) if s == "ret" => InstructionKind::Ret,
+                        TokenValue::Identifier(ref s) if s == "sys" => InstructionKind::Sys,
+                        TokenValue::Identifier(ref s) if s == "len" => InstructionKind::Len,
+                        TokenValue::Identifier(ref s) if s == "ern" => InstructionKind::Ern,
+                        TokenValue::Identifier(ref s) if s == "lnw" => InstructionKind::Lnw,
+                        TokenValue::Identifier(ref s) if s == "lpu" => InstructionKind::Lpu,
+                        TokenValue::Identifier(ref s) if s == "lpo" => InstructionKind::Lpo,
+                        TokenValue::Identifier(ref s) if s == "lgt" => InstructionKind::Lgt,
+                        TokenValue::Identifier(ref s) if s == "lst" => InstructionKind::Lst,
+                        TokenValue::Identifier(ref s) if s == "lsl" => InstructionKind::Lsl,
+                        TokenValue::Identifier(ref s) if s == "mnw" => InstructionKind::Mnw,
+                        TokenValue::Identifier(ref s) if s == "mst" => InstructionKind::Mst,
+                        TokenValue::Identifier(ref s) if s == "mgt" => InstructionKind::Mgt,
+                        TokenValue::Identifier(ref s) if s == "mrm" => InstructionKind::Mrm,
+                        TokenValue::Identifier(ref s) if s == "mhs" => InstructionKind::Mhs,
+                        TokenValue::Identifier(ref s) if s == "mks" => InstructionKind::Mks,
+                        TokenValue::Identifier(ref s) if s == "end" => InstructionKind::End,
+                        TokenValue::Identifier(ref s) if s == "thr" => InstructionKind::Thr,
+                        TokenValue::Identifier(ref s) if s == "agc" => InstructionKind::Agc,
+                        TokenValue::Identifier(ref s) if s == "arg" => InstructionKind::Arg,
+                        TokenValue::Identifier(ref s) if s == "env" => InstructionKind::Env,
+                        _ => return Err(Error::new(format!("Invalid instruction: {:?}", t), t.line, t.col, &None).in_file(&t.file)),
+                    };
+
+                    i += 1;
+
+                    let instruction = Instruction {
+                        kind,
+                        params: vec![],
+                        line: t.line,
+                        col: t.col,
+                        file: t.file.clone(),
+                    };
+
+                    instrs.push(instruction);
+                }
+            },
+            "label" if keyword(&tokens, i, ".const") => {
+                i += 1;
+                let name = expect(&tokens, i, "identifier")?.value.to_string();
+                i += 1;
+                if expect(&tokens, i, "punctuation")?.value.to_string() != "=" {
+                    return Err(Error::new("Expected '=' after constant name".to_string(), t.line, t.col, &None).in_file(&t.file));
+                }
+                i += 1;
+                let value_token = current(&tokens, i).ok_or(Error::new("Expected constant value", t.line, t.col, &None).in_file(&t.file))?;
+                let value = literal(value_token, consts)?;
+                i += 1;
+
+                if consts.contains_key(&name) || name == "true" || name == "false" {
+                    return Err(Error::new(format!("Constant {} already exists", name), t.line, t.col, &None).in_file(&t.file));
+                }
+                consts.insert(name, value);
+            },
+            "label" if keyword(&tokens, i, ".data") => {
+                i += 1;
+                in_data = true;
+            },
+            "label" => {
+                in_data = false;
+                i += 1;
+                if expect(&tokens, i,"punctuation")?.value.to_string() != ":" {
+                    return Err(Error::new("Expected ':' after label".to_string(), t.line, t.col, &None).in_file(&t.file));
+                }
+                i += 1;
+
+                if labels.contains_key(&t.value.to_string()) {
+                    return Err(Error::new(format!("Label {} already exists", t.value), t.line, t.col, &None).in_file(&t.file));
+                }
+
+                labels.insert(t.value.to_string(), instrs.len());
+                instrs.push(Instruction {
+                    kind: InstructionKind::Lbl,
+                    params: vec![ValueType::String(t.value.to_string())],
+                    line: t.line,
+                    col: t.col,
+                    file: t.file.clone(),
+                });
+            },
+            "function" => {
+                in_data = false;
+                i += 1;
+                if expect(&tokens, i,"punctuation")?.value.to_string() != ":" {
+                    return Err(Error::new("Expected ':' after function".to_string(), t.line, t.col, &None).in_file(&t.file));
+                }
+                i += 1;
+
+                if funcs.contains_key(&t.value.to_string()) {
+                    return Err(Error::new(format!("Function {} already exists", t.value), t.line, t.col, &None).in_file(&t.file));
+                }
+
+                funcs.insert(t.value.to_string(), instrs.len());
+                instrs.push(Instruction {
+                    kind: InstructionKind::Fun,
+                    params: vec![ValueType::String(t.value.to_string())],
+                    line: t.line,
+                    col: t.col,
+                    file: t.file.clone(),
+                });
+            },
+            "debugsymbol" => {
+                i += 1;
+                instrs.push(Instruction {
+                    kind: InstructionKind::DebugSymbol,
+                    params: vec![ValueType::DebugSymbol(t.value.as_debug_symbol().unwrap())],
+                    line: t.line,
+                    col: t.col,
+                    file: t.file.clone(),
+                })
+            }
+            "directive" if t.value == TokenValue::Directive("%module".to_string()) => {
+                i += 1;
+                let name = expect(&tokens, i, "identifier")?.value.to_string();
+                i += 1;
+
+                if module.is_some() {
+                    return Err(Error::new("Module name already declared".to_string(), t.line, t.col, &None).in_file(&t.file));
+                }
+                module = Some(name);
+            },
+            "directive" if t.value == TokenValue::Directive("%export".to_string()) => {
+                i += 1;
+                let func = expect(&tokens, i, "function")?.value.to_string();
+                i += 1;
+
+                exports.push((func, t));
+            },
+            _ => {
+                Err(Error::new(format!("Unexpected token: {:?}", t), t.line, t.col, &None).in_file(&t.file))?;
+            }
+        }
+    }
+
+    for (func, t) in &exports {
+        if !funcs.contains_key(func) {
+            return Err(Error::new(format!("Exported function {} not found", func), t.line, t.col, &None).in_file(&t.file));
+        }
+    }
+
+    Ok(ParserRet {
+        instrs,
+        labels,
+        funcs,
+        module,
+        exports: exports.into_iter().map(|(func, _)| func).collect(),
+        data,
+    })
 }
\ No newline at end of file
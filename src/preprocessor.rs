@@ -0,0 +1,225 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use crate::Error;
+use crate::lexer::{self, Token, TokenValue};
+
+struct Includes {
+    done: HashSet<PathBuf>,
+    active: Vec<(PathBuf, String)>,
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+    labels: Vec<String>,
+}
+
+fn identity(path: &str) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path))
+}
+
+fn resolve(from: &str, target: &str) -> String {
+    let base = Path::new(from).parent().unwrap_or(Path::new(""));
+    base.join(target).to_string_lossy().into_owned()
+}
+
+fn expand(code: String, path: &str, includes: &mut Includes) -> Result<Vec<Token>, Error> {
+    let file = Some(path.to_string());
+    let id = identity(path);
+    includes.done.insert(id.clone());
+    includes.active.push((id, path.to_string()));
+
+    let mut tokens = lexer::lex(code).map_err(|e| e.in_file(&file))?;
+    for token in tokens.iter_mut() {
+        token.file = file.clone();
+    }
+
+    let mut expanded: Vec<Token> = Vec::new();
+    let mut i = 0;
+
+    while i < tokens.len() {
+        let t = &tokens[i];
+        if t.value != TokenValue::Directive("%include".to_string()) {
+            expanded.push(t.clone());
+            i += 1;
+            continue;
+        }
+
+        let target = match tokens.get(i + 1) {
+            Some(Token { value: TokenValue::String(s), .. }) => s,
+            _ => return Err(Error::new("Expected file path string after %include", t.line, t.col, &None).in_file(&t.file)),
+        };
+        i += 2;
+
+        let include_path = resolve(path, target);
+        let include_id = identity(&include_path);
+
+        if let Some(start) = includes.active.iter().position(|(id, _)| id == &include_id) {
+            let mut chain: Vec<&str> = includes.active[start..].iter().map(|(_, name)| name.as_str()).collect();
+            chain.push(&include_path);
+            return Err(Error::new(format!("Include cycle: {}", chain.join(" -> ")), t.line, t.col, &None).in_file(&t.file));
+        }
+
+        if includes.done.contains(&include_id) {
+            continue;
+        }
+
+        let code = std::fs::read_to_string(&include_path)
+            .map_err(|e| Error::new(format!("Failed to include '{}': {}", include_path, e), t.line, t.col, &None).in_file(&t.file))?;
+        expanded.extend(expand(code, &include_path, includes)?);
+    }
+
+    includes.active.pop();
+    Ok(expanded)
+}
+
+fn is_punctuation(token: Option<&Token>, c: char) -> bool {
+    token.is_some_and(|t| t.value == TokenValue::Punctuation(c))
+}
+
+// Parses `%macro name(params) ... %endmacro` starting at the directive, returning the macro and the index after it.
+fn define(tokens: &[Token], start: usize) -> Result<(String, Macro, usize), Error> {
+    let t = &tokens[start];
+    let error = |message: &str| Error::new(message, t.line, t.col, &None).in_file(&t.file);
+
+    let name = match tokens.get(start + 1) {
+        Some(Token { value: TokenValue::Identifier(name), .. }) => name.clone(),
+        _ => return Err(error("Expected macro name after %macro")),
+    };
+    if !is_punctuation(tokens.get(start + 2), '(') {
+        return Err(error("Expected '(' after macro name"));
+    }
+
+    let mut params: Vec<String> = Vec::new();
+    let mut i = start + 3;
+    while !is_punctuation(tokens.get(i), ')') {
+        match tokens.get(i) {
+            Some(Token { value: TokenValue::Identifier(param), .. }) => params.push(param.clone()),
+            _ => return Err(error("Expected macro parameter name")),
+        }
+        i += 1;
+        if is_punctuation(tokens.get(i), ',') {
+            i += 1;
+        }
+    }
+    i += 1;
+
+    let mut body: Vec<Token> = Vec::new();
+    loop {
+        match tokens.get(i) {
+            None => return Err(error("Unterminated macro, expected %endmacro")),
+            Some(token) if token.value == TokenValue::Directive("%endmacro".to_string()) => break,
+            Some(token) if token.value == TokenValue::Directive("%macro".to_string()) => {
+                return Err(Error::new("Macros cannot be defined inside a macro", token.line, token.col, &None).in_file(&token.file));
+            },
+            Some(token) => body.push(token.clone()),
+        }
+        i += 1;
+    }
+
+    let labels = body.iter().enumerate()
+        .filter(|(j, token)| token.kind == "label" && is_punctuation(body.get(j + 1), ':'))
+        .map(|(_, token)| token.value.to_string())
+        .collect();
+
+    Ok((name, Macro { params, body, labels }, i + 1))
+}
+
+// Reads the comma separated arguments of an invocation starting at its '(', returning them and the index after ')'.
+fn arguments(tokens: &[Token], start: usize) -> Result<(Vec<Vec<Token>>, usize), Error> {
+    let t = &tokens[start];
+    let mut args: Vec<Vec<Token>> = Vec::new();
+    let mut arg: Vec<Token> = Vec::new();
+    let mut i = start + 1;
+
+    loop {
+        match tokens.get(i) {
+            None => return Err(Error::new("Unterminated macro invocation, expected ')'", t.line, t.col, &None).in_file(&t.file)),
+            Some(token) if token.value == TokenValue::Punctuation(')') => break,
+            Some(token) if token.value == TokenValue::Punctuation(',') => args.push(std::mem::take(&mut arg)),
+            Some(token) => arg.push(token.clone()),
+        }
+        i += 1;
+    }
+
+    if !arg.is_empty() || !args.is_empty() {
+        args.push(arg);
+    }
+
+    Ok((args, i + 1))
+}
+
+fn expand_macros(tokens: &[Token], macros: &mut HashMap<String, Macro>, expansions: &mut usize, depth: usize) -> Result<Vec<Token>, Error> {
+    let mut expanded: Vec<Token> = Vec::new();
+    let mut i = 0;
+
+    while i < tokens.len() {
+        let t = &tokens[i];
+
+        if t.value == TokenValue::Directive("%macro".to_string()) {
+            let (name, definition, end) = define(tokens, i)?;
+            if macros.contains_key(&name) {
+                return Err(Error::new(format!("Macro {} already exists", name), t.line, t.col, &None).in_file(&t.file));
+            }
+            macros.insert(name, definition);
+            i = end;
+            continue;
+        }
+
+        if t.kind != "identifier" || !is_punctuation(tokens.get(i + 1), '(') {
+            expanded.push(t.clone());
+            i += 1;
+            continue;
+        }
+
+        let name = t.value.to_string();
+        let (args, end) = arguments(tokens, i + 1)?;
+        let error = |message: String| Error::new(message, t.line, t.col, &None).in_file(&t.file);
+
+        if depth >= 64 {
+            return Err(error(format!("Macro {} expands too deeply", name)));
+        }
+        let definition = macros.get(&name).ok_or_else(|| error(format!("Macro {} not found", name)))?;
+        if args.len() != definition.params.len() {
+            return Err(error(format!("Macro {} expects {} arguments, got {}", name, definition.params.len(), args.len())));
+        }
+
+        // The depth limit alone lets macros that each invoke the next one twice grow exponentially.
+        if *expansions >= 1 << 16 {
+            return Err(error(format!("Macro {} exceeds the limit of {} expansions", name, 1 << 16)));
+        }
+        *expansions += 1;
+        let mut body: Vec<Token> = Vec::new();
+        for token in &definition.body {
+            if let Some(index) = definition.params.iter().position(|p| token.value == TokenValue::Identifier(p.clone())) {
+                body.extend(args[index].iter().cloned());
+                continue;
+            }
+
+            let mut token = token.clone();
+            if token.kind == "label" && definition.labels.contains(&token.value.to_string()) {
+                token.value = TokenValue::Label(format!("{}__{}_{}", token.value, name, expansions));
+            }
+            token.line = t.line;
+            token.col = t.col;
+            token.file = t.file.clone();
+            body.push(token);
+        }
+
+        expanded.extend(expand_macros(&body, macros, expansions, depth + 1)?);
+        i = end;
+    }
+
+    Ok(expanded)
+}
+
+// Lexes `code`, expands `%include "file"` directives relative to the including file, then expands macros.
+pub fn preprocess(code: String, path: &str) -> Result<Vec<Token>, Error> {
+    let mut includes = Includes {
+        done: HashSet::new(),
+        active: Vec::new(),
+    };
+
+    let tokens = expand(code, path, &mut includes)?;
+    expand_macros(&tokens, &mut HashMap::new(), &mut 0, 0)
+}
@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::Error;
+use crate::disasm;
+use crate::evaluator::Vm;
+use crate::parser::InstructionKind;
+
+#[derive(Default, Clone, Copy)]
+pub struct Counter {
+    pub count: u64,
+    pub time: Duration,
+}
+
+impl Counter {
+    fn add(&mut self, time: Duration) {
+        self.count += 1;
+        self.time += time;
+    }
+}
+
+pub struct Profile {
+    pub instrs: Vec<(String, Counter)>,
+    pub funcs: HashMap<String, Counter>,
+    pub sources: HashMap<String, Counter>,
+    pub stacks: HashMap<String, u64>,
+}
+
+// Runs `vm` to completion, counting executions and wall time per instruction, per function and per
+// Zelkel source location, and the number of instructions executed under each call stack.
+pub fn profile(vm: &mut Vm) -> Result<(i32, Profile), Error> {
+    let mut owners: Vec<String> = Vec::new();
+    let mut owner = "?".to_string();
+    for instr in &vm.program.instrs {
+        if instr.kind == InstructionKind::Fun {
+            owner = instr.params[0].to_string();
+        }
+        owners.push(owner.clone());
+    }
+
+    let mut profile = Profile {
+        instrs: vm.program.instrs.iter().map(|i| (disasm::render(i), Counter::default())).collect(),
+        funcs: HashMap::new(),
+        sources: HashMap::new(),
+        stacks: HashMap::new(),
+    };
+
+    loop {
+        let index = vm.cur;
+        if index >= vm.program.instrs.len() {
+            return Ok((vm.step()?.unwrap_or(0), profile));
+        }
+
+        let mut stack: Vec<&str> = vm.ret_stack.iter().map(|r| owners[*r].as_str()).collect();
+        stack.push(&owners[index]);
+        let stack = stack.join(";");
+        let source = vm.current_debug_symbol.as_ref().map(|ds| format!("{}:{}:{}", ds.path, ds.line, ds.col));
+
+        let start = Instant::now();
+        let exit = vm.step()?;
+        let time = start.elapsed();
+
+        profile.instrs[index].1.add(time);
+        profile.funcs.entry(owners[index].clone()).or_default().add(time);
+        if let Some(source) = source {
+            profile.sources.entry(source).or_default().add(time);
+        }
+        *profile.stacks.entry(stack).or_default() += 1;
+
+        if let Some(code) = exit {
+            return Ok((code, profile));
+        }
+    }
+}
+
+fn section(out: &mut String, title: &str, mut rows: Vec<(String, Counter)>) {
+    rows.retain(|(_, c)| c.count > 0);
+    rows.sort_by(|a, b| b.1.time.cmp(&a.1.time).then(b.1.count.cmp(&a.1.count)).then(a.0.cmp(&b.0)));
+
+    out.push_str(&format!("{}:\n", title));
+    for (name, counter) in rows {
+        out.push_str(&format!("{:>10} {:>12.3?}  {}\n", counter.count, counter.time, name));
+    }
+}
+
+// Renders the profile as three tables (instructions, functions and source locations), each sorted by
+// descending wall time.
+pub fn report(profile: &Profile) -> String {
+    let mut out = String::new();
+    let instrs = profile.instrs.iter().enumerate().map(|(i, (instr, c))| (format!("#{} {}", i, instr), *c)).collect();
+    section(&mut out, "Instructions", instrs);
+    section(&mut out, "Functions", profile.funcs.iter().map(|(n, c)| (n.clone(), *c)).collect());
+    section(&mut out, "Sources", profile.sources.iter().map(|(n, c)| (n.clone(), *c)).collect());
+    out
+}
+
+// Renders the call stacks in the folded format read by flamegraph tools, weighted by the number of
+// instructions executed.
+pub fn folded(profile: &Profile) -> String {
+    let mut stacks: Vec<(&String, &u64)> = profile.stacks.iter().collect();
+    stacks.sort();
+
+    let mut out = String::new();
+    for (stack, count) in stacks {
+        out.push_str(&format!("{} {}\n", stack, count));
+    }
+    out
+}
@@ -0,0 +1,172 @@
+use std::io::{BufRead, Write};
+use crate::Error;
+use crate::disasm;
+use crate::evaluator::Vm;
+use crate::lexer;
+use crate::parser;
+
+const HELP: &str = "Enter instructions to run them immediately. A line starting with @name: defines a function,
+which ends at the next empty line. Labels can be defined and jumped to like in a file.
+  :vars     show variables
+  :bufs     show buffers
+  :source   show everything entered so far
+  :quit     leave the REPL";
+
+// The session is kept as the body of `@entry`, followed by the functions defined in it.
+const HEADER: &str = "@entry:\n";
+
+pub struct Repl {
+    pub vm: Vm,
+    source: String,
+    pending: Option<String>,
+    scope: parser::Scope,
+    // Lines entered so far, to number the next ones.
+    lines: usize,
+    // Where the next top-level instructions go: after the earlier ones and before every function, so
+    // neither runs into the other.
+    top: usize,
+}
+
+impl Repl {
+    pub fn new() -> Result<Self, Error> {
+        let program = parser::parse(lexer::lex(HEADER.to_string())?)?;
+        Ok(Self {
+            top: program.instrs.len(),
+            vm: Vm::new(program)?,
+            source: String::new(),
+            pending: None,
+            scope: parser::Scope::default(),
+            lines: 0,
+        })
+    }
+
+    // Whether a function definition is waiting for its closing empty line.
+    pub fn defining(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    // Adds parsed instructions to the program at `at`, moving everything after them.
+    fn insert(&mut self, chunk: parser::ParserRet, at: usize) -> Result<(), Error> {
+        let program = &mut self.vm.program;
+        for (name, index) in &chunk.labels {
+            if program.labels.contains_key(name) {
+                let instr = &chunk.instrs[*index];
+                return Err(Error::new(format!("Label {} already exists", name), instr.line, instr.col, &None));
+            }
+        }
+        for (name, index) in &chunk.funcs {
+            if program.funcs.contains_key(name) {
+                let instr = &chunk.instrs[*index];
+                return Err(Error::new(format!("Function {} already exists", name), instr.line, instr.col, &None));
+            }
+        }
+
+        let len = chunk.instrs.len();
+        for index in program.labels.values_mut().chain(program.funcs.values_mut()).chain(self.vm.ret_stack.iter_mut()) {
+            if *index >= at {
+                *index += len;
+            }
+        }
+        for handler in self.vm.handlers.iter_mut().filter(|h| h.label >= at) {
+            handler.label += len;
+        }
+        program.labels.extend(chunk.labels.into_iter().map(|(name, index)| (name, index + at)));
+        program.funcs.extend(chunk.funcs.into_iter().map(|(name, index)| (name, index + at)));
+        program.instrs.splice(at..at, chunk.instrs);
+        Ok(())
+    }
+
+    // Parses `line` on its own against the names declared so far and, outside of function definitions,
+    // runs its instructions. Returns the exit code when they end in a top-level `ret`.
+    pub fn eval(&mut self, line: &str) -> Result<Option<i32>, Error> {
+        let starts_function = lexer::lex(line.to_string())
+            .is_ok_and(|tokens| tokens.len() >= 2 && tokens[0].kind == "function" && tokens[1].value == lexer::TokenValue::Punctuation(':'));
+
+        let (code, definition) = match self.pending.take() {
+            Some(mut pending) if !line.trim().is_empty() => {
+                pending.push_str(line);
+                pending.push('\n');
+                self.pending = Some(pending);
+                return Ok(None);
+            },
+            Some(pending) => (pending, true),
+            None if starts_function => {
+                self.pending = Some(format!("{}\n", line));
+                return Ok(None);
+            },
+            None => (format!("{}\n", line), false),
+        };
+
+        let offset = self.lines;
+        let mut tokens = lexer::lex(code.clone()).map_err(|mut err| {
+            err.line += offset;
+            err
+        })?;
+        for token in &mut tokens {
+            token.line += offset;
+        }
+        let mut scope = self.scope.clone();
+        let chunk = parser::parse_in_scope(tokens, &mut scope)?;
+
+        let len = chunk.instrs.len();
+        let at = if definition { self.vm.program.instrs.len() } else { self.top };
+        self.insert(chunk, at)?;
+        self.scope = scope;
+        self.lines += code.lines().count();
+        self.source.push_str(&code);
+        if definition {
+            return Ok(None);
+        }
+
+        self.vm.cur = self.top;
+        self.top += len;
+        while self.vm.cur != self.top {
+            if let Some(code) = self.vm.step()? {
+                self.vm.exit = None;
+                return Ok(Some(code));
+            }
+        }
+        Ok(None)
+    }
+}
+
+// Reads instructions from `input` and runs them one line at a time, printing the stack after each.
+pub fn repl<R: BufRead, W: Write>(input: R, output: &mut W) -> std::io::Result<()> {
+    let mut repl = Repl::new().map_err(|e| std::io::Error::other(format!("{:?}", e)))?;
+
+    write!(output, "zvm> ")?;
+    output.flush()?;
+    for line in input.lines() {
+        let line = line?;
+        match line.trim() {
+            ":quit" | ":q" => break,
+            ":help" | ":h" => writeln!(output, "{}", HELP)?,
+            ":source" => write!(output, "{}", repl.source)?,
+            ":vars" => {
+                let mut vars: Vec<_> = repl.vm.vars.iter().collect();
+                vars.sort_by(|a, b| a.0.cmp(b.0));
+                for (name, value) in vars {
+                    writeln!(output, "{} = {}", name, disasm::literal(value))?;
+                }
+            },
+            ":bufs" => {
+                let mut bufs: Vec<_> = repl.vm.bufs.iter().collect();
+                bufs.sort_by(|a, b| a.0.cmp(b.0));
+                for (name, buf) in bufs {
+                    writeln!(output, "{} [{} bytes] {:?}", name, buf.size, String::from_utf8_lossy(&buf.data))?;
+                }
+            },
+            _ => match repl.eval(&line) {
+                Ok(Some(code)) => writeln!(output, "Exited with code {}", code)?,
+                Ok(None) if repl.defining() => {},
+                Ok(None) => writeln!(output, "{:?}", repl.vm.stack)?,
+                Err(err) => writeln!(output, "Error: {:?}", err)?,
+            },
+        }
+
+        write!(output, "{}", if repl.defining() { "...> " } else { "zvm> " })?;
+        output.flush()?;
+    }
+
+    Ok(())
+}
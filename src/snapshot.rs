@@ -0,0 +1,315 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+use crate::{Error, ErrorKind};
+use crate::disasm;
+use crate::evaluator::{self, Buffer, Handler, Limits, Vm};
+use crate::lexer::{self, Token, TokenValue};
+use crate::parser::{self, MapKey, ParserRet, ValueType};
+
+const HEADER: &str = "zvm-snapshot 1";
+
+// FNV-1a over the canonical source, so a snapshot is only restored into the program it was taken from.
+fn fingerprint(program: &ParserRet) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in disasm::disasm(program).bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+fn value(text: &str, line: usize, refs: &HashMap<usize, ValueType>) -> Result<ValueType, Error> {
+    let tokens = lexer::lex(text.to_string()).map_err(|e| Error::new(e.message, line, e.col, &None))?;
+    let invalid = || Error::new(format!("Invalid snapshot value: {}", text), line, 0, &None);
+    let mut pos = 0;
+    let value = item(&tokens, &mut pos, line, refs).map_err(|_| invalid())?;
+    if pos != tokens.len() {
+        return Err(invalid());
+    }
+    Ok(value)
+}
+
+// Lists and maps inside values are written as `refN`, naming a `list N` or `map N` entry.
+fn item(tokens: &[Token], pos: &mut usize, line: usize, refs: &HashMap<usize, ValueType>) -> Result<ValueType, Error> {
+    let t = tokens.get(*pos).ok_or(Error::new("Expected a value", line, 0, &None))?;
+    *pos += 1;
+    match &t.value {
+        TokenValue::Punctuation('[') => {
+            let mut items = Vec::new();
+            if tokens.get(*pos).map(|t| &t.value) == Some(&TokenValue::Punctuation(']')) {
+                *pos += 1;
+                return Ok(ValueType::List(Rc::new(RefCell::new(items))));
+            }
+            loop {
+                items.push(item(tokens, pos, line, refs)?);
+                *pos += 1;
+                match tokens.get(*pos - 1).map(|t| &t.value) {
+                    Some(TokenValue::Punctuation(',')) => {},
+                    Some(TokenValue::Punctuation(']')) => return Ok(ValueType::List(Rc::new(RefCell::new(items)))),
+                    _ => return Err(Error::new("Expected ',' or ']'", line, 0, &None)),
+                }
+            }
+        },
+        TokenValue::Punctuation('{') => {
+            let mut entries = BTreeMap::new();
+            if tokens.get(*pos).map(|t| &t.value) == Some(&TokenValue::Punctuation('}')) {
+                *pos += 1;
+                return Ok(ValueType::Map(Rc::new(RefCell::new(entries))));
+            }
+            loop {
+                let key = MapKey::new(&item(tokens, pos, line, refs)?).map_err(|e| Error::new(e, line, 0, &None))?;
+                if tokens.get(*pos).map(|t| &t.value) != Some(&TokenValue::Punctuation(':')) {
+                    return Err(Error::new("Expected ':'", line, 0, &None));
+                }
+                *pos += 1;
+                entries.insert(key, item(tokens, pos, line, refs)?);
+                *pos += 1;
+                match tokens.get(*pos - 1).map(|t| &t.value) {
+                    Some(TokenValue::Punctuation(',')) => {},
+                    Some(TokenValue::Punctuation('}')) => return Ok(ValueType::Map(Rc::new(RefCell::new(entries)))),
+                    _ => return Err(Error::new("Expected ',' or '}'", line, 0, &None)),
+                }
+            }
+        },
+        TokenValue::Identifier(s) if s.starts_with("ref") => {
+            let id = s[3..].parse::<usize>().ok().and_then(|id| refs.get(&id));
+            id.cloned().ok_or(Error::new(format!("Unknown reference {}", s), line, 0, &None))
+        },
+        TokenValue::Identifier(s) if s == "neg_inf" => Ok(ValueType::Float(f32::NEG_INFINITY)),
+        TokenValue::Identifier(s) if s == "NaN" => Ok(ValueType::Float(f32::NAN)),
+        TokenValue::Identifier(s) if s == "inf" => Ok(ValueType::Float(f32::INFINITY)),
+        TokenValue::Buffer(b) => Ok(ValueType::Buffer(b.clone())),
+        TokenValue::Variable(v) => Ok(ValueType::Variable(v.clone())),
+        TokenValue::DebugSymbol(ds) => Ok(ValueType::DebugSymbol(ds.clone())),
+        _ => parser::literal(t, &HashMap::new()).map_err(|e| Error::new(e.message, line, 0, &None)),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str, line: usize) -> Result<Vec<u8>, Error> {
+    let error = || Error::new(format!("Invalid buffer contents: {}", text), line, 0, &None);
+    if !text.len().is_multiple_of(2) {
+        return Err(error());
+    }
+    (0..text.len()).step_by(2)
+        .map(|i| text.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()).ok_or_else(error))
+        .collect()
+}
+
+fn longest_string(value: &ValueType) -> usize {
+    match value {
+        ValueType::String(s) => s.len(),
+        ValueType::List(l) => l.borrow().iter().map(longest_string).max().unwrap_or(0),
+        ValueType::Map(m) => m.borrow().iter().map(|(k, v)| longest_string(&k.to_value()).max(longest_string(v))).max().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+fn most_items(value: &ValueType) -> usize {
+    match value {
+        ValueType::List(l) => l.borrow().iter().map(most_items).max().unwrap_or(0).max(l.borrow().len()),
+        ValueType::Map(m) => m.borrow().values().map(most_items).max().unwrap_or(0).max(m.borrow().len()),
+        _ => 0,
+    }
+}
+
+// Rejects restored state that the VM could not have reached under `limits`.
+fn check_limits(limits: &Limits, stack: &[ValueType], ret_stack: &[usize], vars: &HashMap<String, ValueType>, bufs: &HashMap<String, Buffer>) -> Result<(), Error> {
+    let exceeded = if stack.len() > limits.stack {
+        Some(format!("stack limit of {} values", limits.stack))
+    } else if ret_stack.len() > limits.ret_stack {
+        Some(format!("return stack limit of {} calls", limits.ret_stack))
+    } else if vars.len() > limits.vars {
+        Some(format!("variable limit of {}", limits.vars))
+    } else if bufs.values().map(|b| b.size).sum::<usize>() > limits.buffer_bytes {
+        Some(format!("buffer limit of {} bytes", limits.buffer_bytes))
+    } else if stack.iter().chain(vars.values()).filter_map(|v| evaluator::nesting(v, std::ptr::null())).max().unwrap_or(0) > limits.depth {
+        Some(format!("depth limit of {}", limits.depth))
+    } else if stack.iter().chain(vars.values()).map(longest_string).max().unwrap_or(0) > limits.string_len {
+        Some(format!("string length limit of {}", limits.string_len))
+    } else if stack.iter().chain(vars.values()).map(most_items).max().unwrap_or(0) > limits.items {
+        Some(format!("item limit of {}", limits.items))
+    } else {
+        None
+    };
+
+    match exceeded {
+        Some(limit) => Err(Error::new(format!("Snapshot exceeds the {}", limit), 0, 0, &None).with_kind(ErrorKind::LimitExceeded)),
+        None => Ok(()),
+    }
+}
+
+// Renders a value, writing each list or map once as a `list N` or `map N` entry and referring to it as
+// `refN`, so containers shared between values stay shared after a restore.
+fn render(value: &ValueType, containers: &mut HashMap<usize, usize>, entries: &mut Vec<String>) -> String {
+    let ptr = match value {
+        ValueType::List(items) => Rc::as_ptr(items) as usize,
+        ValueType::Map(entries) => Rc::as_ptr(entries) as usize,
+        // The lexer reads `-inf` as a minus sign followed by an identifier.
+        ValueType::Float(f) if *f == f32::NEG_INFINITY => return "neg_inf".to_string(),
+        _ => return disasm::literal(value),
+    };
+    if let Some(id) = containers.get(&ptr) {
+        return format!("ref{}", id);
+    }
+
+    let id = containers.len();
+    containers.insert(ptr, id);
+    let index = entries.len();
+    entries.push(String::new());
+    entries[index] = match value {
+        ValueType::List(items) => {
+            let items: Vec<String> = items.borrow().iter().map(|v| render(v, containers, entries)).collect();
+            format!("list {} [{}]\n", id, items.join(", "))
+        },
+        ValueType::Map(map) => {
+            let items: Vec<String> = map.borrow().iter()
+                .map(|(k, v)| format!("{}: {}", disasm::literal(&k.to_value()), render(v, containers, entries)))
+                .collect();
+            format!("map {} {{{}}}\n", id, items.join(", "))
+        },
+        _ => unreachable!(),
+    };
+    format!("ref{}", id)
+}
+
+// Serializes the VM state: instruction pointer, exit code, current debug symbol, return stack, `try`
+// handlers, stack, variables, lists and maps and buffer contents. The program itself is not included, only its fingerprint.
+pub fn save(vm: &Vm) -> String {
+    let mut out = format!("{}\nprogram {}\ncur {}\n", HEADER, fingerprint(&vm.program), vm.cur);
+    if let Some(code) = vm.exit {
+        out.push_str(&format!("exit {}\n", code));
+    }
+    if let Some(ds) = &vm.current_debug_symbol {
+        out.push_str(&format!("debug_symbol {}\n", disasm::literal(&ValueType::DebugSymbol(ds.clone()))));
+    }
+    for ret in &vm.ret_stack {
+        out.push_str(&format!("ret {}\n", ret));
+    }
+    for handler in &vm.handlers {
+        out.push_str(&format!("try {} {} {}\n", handler.label, handler.stack, handler.calls));
+    }
+    let mut containers = HashMap::new();
+    let mut entries = Vec::new();
+    for value in &vm.stack {
+        out.push_str(&format!("stack {}\n", render(value, &mut containers, &mut entries)));
+    }
+
+    let mut vars: Vec<_> = vm.vars.iter().collect();
+    vars.sort_by(|a, b| a.0.cmp(b.0));
+    for (name, value) in vars {
+        out.push_str(&format!("var {} {}\n", name, render(value, &mut containers, &mut entries)));
+    }
+    for entry in entries {
+        out.push_str(&entry);
+    }
+
+    let mut bufs: Vec<_> = vm.bufs.iter().collect();
+    bufs.sort_by(|a, b| a.0.cmp(b.0));
+    for (name, buf) in bufs {
+        out.push_str(&format!("buf {} {}\n", name, hex(&buf.data)));
+    }
+
+    out
+}
+
+// Replaces the state of `vm` with a snapshot taken by `save` from the same program. Limits, fuel and
+// deadline are left as they are, and the snapshot has to fit the limits.
+pub fn restore(vm: &mut Vm, snapshot: &str) -> Result<(), Error> {
+    let mut lines = snapshot.lines().enumerate().map(|(n, l)| (n + 1, l));
+    if lines.next().map(|(_, l)| l) != Some(HEADER) {
+        return Err(Error::new("Not a snapshot", 1, 0, &None));
+    }
+
+    let mut cur = None;
+    let mut exit = None;
+    let mut current_debug_symbol = None;
+    let mut ret_stack: Vec<usize> = Vec::new();
+    let mut handlers: Vec<Handler> = Vec::new();
+    let mut stack: Vec<ValueType> = Vec::new();
+    let mut vars: HashMap<String, ValueType> = HashMap::new();
+    let mut bufs: HashMap<String, Buffer> = HashMap::new();
+
+    // Every list and map is created up front, since values can refer to ones written after them.
+    let lines: Vec<(usize, &str)> = lines.collect();
+    let mut refs: HashMap<usize, ValueType> = HashMap::new();
+    for (line, text) in &lines {
+        let container = match text.split_once(' ') {
+            Some(("list", rest)) => (rest, ValueType::List(Rc::new(RefCell::new(Vec::new())))),
+            Some(("map", rest)) => (rest, ValueType::Map(Rc::new(RefCell::new(BTreeMap::new())))),
+            _ => continue,
+        };
+        let id = container.0.split(' ').next().unwrap_or_default();
+        let id = id.parse::<usize>().map_err(|_| Error::new(format!("Invalid index: {}", id), *line, 0, &None))?;
+        refs.insert(id, container.1);
+    }
+
+    for (line, text) in lines {
+        let (key, rest) = text.split_once(' ').unwrap_or((text, ""));
+        let index = |s: &str| s.parse::<usize>().map_err(|_| Error::new(format!("Invalid index: {}", s), line, 0, &None));
+        match key {
+            "program" if rest != fingerprint(&vm.program) => {
+                return Err(Error::new("Snapshot was taken from a different program", line, 0, &None));
+            },
+            "program" => {},
+            "cur" => cur = Some(index(rest)?),
+            "exit" => exit = Some(rest.parse::<i32>().map_err(|_| Error::new(format!("Invalid exit code: {}", rest), line, 0, &None))?),
+            "debug_symbol" => current_debug_symbol = Some(value(rest, line, &refs)?.as_debug_symbol().map_err(|e| Error::new(e, line, 0, &None))?),
+            "ret" => ret_stack.push(index(rest)?),
+            "try" => {
+                let fields = rest.split(' ').map(index).collect::<Result<Vec<usize>, Error>>()?;
+                let [label, stack, calls] = fields[..] else {
+                    return Err(Error::new("Expected a label index, stack depth and call depth", line, 0, &None));
+                };
+                handlers.push(Handler { label, stack, calls });
+            },
+            "stack" => stack.push(value(rest, line, &refs)?),
+            "var" => {
+                let (name, v) = rest.split_once(' ').ok_or(Error::new("Expected a variable name and value", line, 0, &None))?;
+                vars.insert(name.to_string(), value(v, line, &refs)?);
+            },
+            "list" | "map" => {
+                let (id, v) = rest.split_once(' ').ok_or(Error::new(format!("Expected an index and a {}", key), line, 0, &None))?;
+                match (&refs[&index(id)?], value(v, line, &refs)?) {
+                    (ValueType::List(target), ValueType::List(items)) => *target.borrow_mut() = items.take(),
+                    (ValueType::Map(target), ValueType::Map(entries)) => *target.borrow_mut() = entries.take(),
+                    _ => return Err(Error::new(format!("Invalid {}: {}", key, v), line, 0, &None)),
+                }
+            },
+            "buf" => {
+                let (name, contents) = rest.split_once(' ').unwrap_or((rest, ""));
+                let mut data = unhex(contents, line)?;
+                let size = data.len();
+                let ptr = data.as_mut_ptr() as usize;
+                bufs.insert(name.to_string(), Buffer { data, size, ptr });
+            },
+            _ => return Err(Error::new(format!("Unknown snapshot entry: {}", key), line, 0, &None)),
+        }
+    }
+
+    let len = vm.program.instrs.len();
+    let cur = cur.ok_or(Error::new("Snapshot has no instruction pointer", 0, 0, &None))?;
+    if cur > len || ret_stack.iter().any(|r| *r >= len) || handlers.iter().any(|h| h.label >= len) {
+        return Err(Error::new("Snapshot points outside of the program", 0, 0, &None));
+    }
+
+    // A written `refN` can make a list or map that contains itself, which the VM never allows.
+    if refs.values().any(|v| evaluator::nesting(v, std::ptr::null()).is_none()) {
+        return Err(Error::new("Snapshot has a list or map that contains itself", 0, 0, &None));
+    }
+    check_limits(&vm.limits, &stack, &ret_stack, &vars, &bufs)?;
+
+    vm.cur = cur;
+    vm.exit = exit;
+    vm.current_debug_symbol = current_debug_symbol;
+    vm.ret_stack = ret_stack;
+    vm.handlers = handlers;
+    vm.stack = stack;
+    vm.vars = vars;
+    vm.bufs = bufs;
+    Ok(())
+}
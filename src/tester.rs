@@ -0,0 +1,98 @@
+use std::io::Write;
+use std::time::{Duration, Instant};
+use crate::Error;
+use crate::evaluator::Vm;
+use crate::linker;
+use crate::parser::ParserRet;
+
+pub struct TestResult {
+    pub name: String,
+    pub failure: Option<String>,
+    pub time: Duration,
+}
+
+// Functions named `@test_*`, or `@module::test_*` once linked, in the order they are defined.
+pub fn discover(program: &ParserRet) -> Vec<String> {
+    let mut tests: Vec<(&String, &usize)> = program.funcs.iter()
+        .filter(|(name, _)| name.rsplit("::").next().unwrap_or(name).trim_start_matches('@').starts_with("test_"))
+        .collect();
+    tests.sort_by_key(|(_, index)| **index);
+    tests.into_iter().map(|(name, _)| name.clone()).collect()
+}
+
+// Runs every test whose name contains `filter` in a fresh VM prepared by `setup`. A test passes when
+// it returns 0.
+pub fn run(program: &ParserRet, filter: &str, setup: impl Fn(&mut Vm)) -> Vec<TestResult> {
+    run_tests(program, discover(program), filter, &setup)
+}
+
+// Runs the tests of each file in its own program, linked with the `%module` files among `files`, so
+// files that each have an `@entry` or labels of the same name can be tested together.
+pub fn run_files(files: &[ParserRet], filter: &str, setup: impl Fn(&mut Vm)) -> Result<Vec<TestResult>, Error> {
+    let mut results: Vec<TestResult> = Vec::new();
+
+    for (i, file) in files.iter().enumerate() {
+        let modules = files.iter().enumerate().filter(|(j, m)| *j != i && m.module.is_some()).map(|(_, m)| m.clone());
+        let program = linker::link_library(std::iter::once(file.clone()).chain(modules).collect())?;
+        // The file is linked first, so its functions are the ones before the end of its instructions.
+        let tests = discover(&program).into_iter().filter(|name| program.funcs[name] < file.instrs.len()).collect();
+        results.extend(run_tests(&program, tests, filter, &setup));
+    }
+
+    Ok(results)
+}
+
+fn run_tests(program: &ParserRet, tests: Vec<String>, filter: &str, setup: &impl Fn(&mut Vm)) -> Vec<TestResult> {
+    let mut results: Vec<TestResult> = Vec::new();
+
+    for name in tests.into_iter().filter(|name| name.contains(filter)) {
+        let start = Instant::now();
+        let failure = match Vm::with_entry(program.clone(), &name) {
+            Ok(mut vm) => {
+                setup(&mut vm);
+                match vm.run() {
+                    Ok(0) => None,
+                    Ok(code) => Some(format!("returned {}", code)),
+                    Err(err) => Some(format!("{:?}", err)),
+                }
+            },
+            Err(err) => Some(format!("{:?}", err)),
+        };
+
+        results.push(TestResult {
+            name,
+            failure,
+            time: start.elapsed(),
+        });
+    }
+
+    results
+}
+
+// Prints one line per test followed by the failures and a summary. Returns whether every test passed.
+pub fn report<W: Write>(results: &[TestResult], output: &mut W) -> std::io::Result<bool> {
+    for result in results {
+        let status = if result.failure.is_some() { "FAILED" } else { "ok" };
+        writeln!(output, "test {} ... {} ({:.3?})", result.name, status, result.time)?;
+    }
+
+    let failed: Vec<&TestResult> = results.iter().filter(|r| r.failure.is_some()).collect();
+    if !failed.is_empty() {
+        writeln!(output, "\nfailures:")?;
+        for result in &failed {
+            writeln!(output, "    {}: {}", result.name, result.failure.as_deref().unwrap_or_default())?;
+        }
+    }
+
+    let total: Duration = results.iter().map(|r| r.time).sum();
+    writeln!(
+        output,
+        "\ntest result: {}. {} passed; {} failed; finished in {:.3?}",
+        if failed.is_empty() { "ok" } else { "FAILED" },
+        results.len() - failed.len(),
+        failed.len(),
+        total,
+    )?;
+
+    Ok(failed.is_empty())
+}
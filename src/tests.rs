@@ -1,113 +1,1070 @@
-use super::*;
-
-#[test]
-fn lex_push_int() {
-    let result = lexer::lex("psh 5".to_string());
-    assert_eq!(result, Ok(vec![
-        lexer::Token { kind: "identifier", value: lexer::TokenValue::Identifier("psh".to_string()) },
-        lexer::Token { kind: "integer", value: lexer::TokenValue::Integer(5) },
-    ]));
-}
-
-#[test]
-fn lex_push_unknown_char_error() {
-    let result = lexer::lex("psh ?".to_string());
-    assert_eq!(result, Err("Unexpected character: '?'".to_string()));
-}
-
-#[test]
-fn lex_push_string() {
-    let result = lexer::lex("psh \"hello\"".to_string());
-    assert_eq!(result, Ok(vec![
-        lexer::Token { kind: "identifier", value: lexer::TokenValue::Identifier("psh".to_string()) },
-        lexer::Token { kind: "string", value: lexer::TokenValue::String("hello".to_string()) },
-    ]));
-}
-
-#[test]
-fn lex_push_float() {
-    let result = lexer::lex("psh 3.14".to_string());
-    assert_eq!(result, Ok(vec![
-        lexer::Token { kind: "identifier", value: lexer::TokenValue::Identifier("psh".to_string()) },
-        lexer::Token { kind: "float", value: lexer::TokenValue::Float(3.14) },
-    ]));
-}
-
-#[test]
-fn parse_push_int() {
-    let tokens = vec![
-        lexer::Token { kind: "identifier", value: lexer::TokenValue::Identifier("psh".to_string()) },
-        lexer::Token { kind: "integer", value: lexer::TokenValue::Integer(5) },
-    ];
-    let result = parser::parse(tokens);
-    assert_eq!(result, Ok(vec![
-            parser::Instruction {
-                kind: parser::InstructionKind::Psh(),
-                params: vec![parser::ValueType::Integer(5)],
-            }
-    ]));
-}
-
-#[test]
-fn parse_push_float() {
-    let tokens = vec![
-        lexer::Token { kind: "identifier", value: lexer::TokenValue::Identifier("psh".to_string()) },
-        lexer::Token { kind: "float", value: lexer::TokenValue::Float(3.14) },
-    ];
-    let result = parser::parse(tokens);
-    assert_eq!(result, Ok(vec![
-        parser::Instruction {
-            kind: parser::InstructionKind::Psh(),
-            params: vec![parser::ValueType::Float(3.14)],
-        }
-    ]));
-}
-
-#[test]
-fn parse_push_string() {
-    let tokens = vec![
-        lexer::Token { kind: "identifier", value: lexer::TokenValue::Identifier("psh".to_string()) },
-        lexer::Token { kind: "string", value: lexer::TokenValue::String("hello".to_string()) },
-    ];
-    let result = parser::parse(tokens);
-    assert_eq!(result, Ok(vec![
-        parser::Instruction {
-            kind: parser::InstructionKind::Psh(),
-            params: vec![parser::ValueType::String("hello".to_string())],
-        }
-    ]));
-}
-
-#[test]
-fn parse_add() {
-    let tokens = vec![
-        lexer::Token { kind: "identifier", value: lexer::TokenValue::Identifier("add".to_string()) },
-    ];
-    let result = parser::parse(tokens);
-    assert_eq!(result, Ok(vec![
-        parser::Instruction {
-            kind: parser::InstructionKind::Add,
-            params: vec![],
-        }
-    ]));
-}
-
-#[test]
-fn evaluate_5_plus_3() {
-    let nodes = vec![
-        parser::Instruction {
-            kind: parser::InstructionKind::Psh(),
-            params: vec![parser::ValueType::Integer(5)],
-        },
-        parser::Instruction {
-            kind: parser::InstructionKind::Psh(),
-            params: vec![parser::ValueType::Integer(3)],
-        },
-        parser::Instruction {
-            kind: parser::InstructionKind::Add,
-            params: vec![],
-        },
-    ];
-    let result = evaluator::evaluate(nodes);
-    assert_eq!(result, Ok(vec![parser::ValueType::Integer(8)]));
-}
\ No newline at end of file
+use super::*;
+
+fn entry_tokens() -> Vec<lexer::Token> {
+    vec![
+        lexer::Token { kind: "function", value: lexer::TokenValue::Function("@entry".to_string()), line: 1, col: 0, file: None },
+        lexer::Token { kind: "punctuation", value: lexer::TokenValue::Punctuation(':'), line: 1, col: 6, file: None },
+    ]
+}
+
+#[test]
+fn lex_push_int() {
+    let result = lexer::lex("psh 5".to_string());
+    assert_eq!(result.unwrap(), vec![
+        lexer::Token { kind: "identifier", value: lexer::TokenValue::Identifier("psh".to_string()), line: 1, col: 0, file: None },
+        lexer::Token { kind: "integer", value: lexer::TokenValue::Integer(5), line: 1, col: 4, file: None },
+    ]);
+}
+
+#[test]
+fn lex_push_unknown_char_error() {
+    let result = lexer::lex("psh ?".to_string());
+    assert_eq!(result.unwrap_err().message, "Unexpected character: '?'".to_string());
+}
+
+#[test]
+fn lex_push_string() {
+    let result = lexer::lex("psh \"hello\"".to_string());
+    assert_eq!(result.unwrap(), vec![
+        lexer::Token { kind: "identifier", value: lexer::TokenValue::Identifier("psh".to_string()), line: 1, col: 0, file: None },
+        lexer::Token { kind: "string", value: lexer::TokenValue::String("hello".to_string()), line: 1, col: 4, file: None },
+    ]);
+}
+
+#[test]
+fn lex_push_float() {
+    let result = lexer::lex("psh 2.5".to_string());
+    assert_eq!(result.unwrap(), vec![
+        lexer::Token { kind: "identifier", value: lexer::TokenValue::Identifier("psh".to_string()), line: 1, col: 0, file: None },
+        lexer::Token { kind: "float", value: lexer::TokenValue::Float(2.5), line: 1, col: 4, file: None },
+    ]);
+}
+
+#[test]
+fn parse_push_int() {
+    let mut tokens = entry_tokens();
+    tokens.extend(vec![
+        lexer::Token { kind: "identifier", value: lexer::TokenValue::Identifier("psh".to_string()), line: 2, col: 4, file: None },
+        lexer::Token { kind: "integer", value: lexer::TokenValue::Integer(5), line: 2, col: 8, file: None },
+    ]);
+    let result = parser::parse(tokens).unwrap();
+    assert_eq!(result.instrs[1], parser::Instruction {
+        kind: parser::InstructionKind::Psh,
+        params: vec![parser::ValueType::Integer(5)],
+        line: 2,
+        col: 4,
+        file: None,
+    });
+}
+
+#[test]
+fn parse_push_float() {
+    let mut tokens = entry_tokens();
+    tokens.extend(vec![
+        lexer::Token { kind: "identifier", value: lexer::TokenValue::Identifier("psh".to_string()), line: 2, col: 4, file: None },
+        lexer::Token { kind: "float", value: lexer::TokenValue::Float(2.5), line: 2, col: 8, file: None },
+    ]);
+    let result = parser::parse(tokens).unwrap();
+    assert_eq!(result.instrs[1], parser::Instruction {
+        kind: parser::InstructionKind::Psh,
+        params: vec![parser::ValueType::Float(2.5)],
+        line: 2,
+        col: 4,
+        file: None,
+    });
+}
+
+#[test]
+fn parse_push_string() {
+    let mut tokens = entry_tokens();
+    tokens.extend(vec![
+        lexer::Token { kind: "identifier", value: lexer::TokenValue::Identifier("psh".to_string()), line: 2, col: 4, file: None },
+        lexer::Token { kind: "string", value: lexer::TokenValue::String("hello".to_string()), line: 2, col: 8, file: None },
+    ]);
+    let result = parser::parse(tokens).unwrap();
+    assert_eq!(result.instrs[1], parser::Instruction {
+        kind: parser::InstructionKind::Psh,
+        params: vec![parser::ValueType::String("hello".to_string())],
+        line: 2,
+        col: 4,
+        file: None,
+    });
+}
+
+#[test]
+fn parse_add() {
+    let mut tokens = entry_tokens();
+    tokens.extend(vec![
+        lexer::Token { kind: "identifier", value: lexer::TokenValue::Identifier("add".to_string()), line: 2, col: 4, file: None },
+    ]);
+    let result = parser::parse(tokens).unwrap();
+    assert_eq!(result.instrs[1], parser::Instruction {
+        kind: parser::InstructionKind::Add,
+        params: vec![],
+        line: 2,
+        col: 4,
+        file: None,
+    });
+}
+
+#[test]
+fn evaluate_5_plus_3() {
+    let tokens = lexer::lex("@entry:\n    psh 5\n    psh 3\n    add".to_string()).unwrap();
+    let parsed = parser::parse(tokens).unwrap();
+    let result = evaluator::evaluate(parsed).unwrap();
+    assert_eq!(result, (vec![parser::ValueType::Integer(8)], 0));
+}
+
+#[test]
+fn lex_negative_numbers() {
+    let result = lexer::lex("psh -1 psh -2.5 psh -.5".to_string()).unwrap();
+    let values: Vec<lexer::TokenValue> = result.into_iter().map(|t| t.value).collect();
+    assert_eq!(values, vec![
+        lexer::TokenValue::Identifier("psh".to_string()),
+        lexer::TokenValue::Integer(-1),
+        lexer::TokenValue::Identifier("psh".to_string()),
+        lexer::TokenValue::Float(-2.5),
+        lexer::TokenValue::Identifier("psh".to_string()),
+        lexer::TokenValue::Float(-0.5),
+    ]);
+}
+
+#[test]
+fn lex_radix_integers() {
+    let result = lexer::lex("0x1F 0b101 0o17 -0x10".to_string()).unwrap();
+    let values: Vec<lexer::TokenValue> = result.into_iter().map(|t| t.value).collect();
+    assert_eq!(values, vec![
+        lexer::TokenValue::Integer(31),
+        lexer::TokenValue::Integer(5),
+        lexer::TokenValue::Integer(15),
+        lexer::TokenValue::Integer(-16),
+    ]);
+}
+
+#[test]
+fn lex_invalid_radix_integer_error() {
+    let result = lexer::lex("psh 0b102".to_string());
+    assert_eq!(result.unwrap_err().message, "Invalid integer: '0b102'".to_string());
+}
+
+#[test]
+fn lex_integer_out_of_range_error() {
+    let result = lexer::lex("psh 0xFFFFFFFF".to_string());
+    assert_eq!(result.unwrap_err().message, "Integer out of range: '0xFFFFFFFF'".to_string());
+}
+
+#[test]
+fn lex_scientific_floats() {
+    let result = lexer::lex("1e3 2.5E-2 -1.5e+2".to_string()).unwrap();
+    let values: Vec<lexer::TokenValue> = result.into_iter().map(|t| t.value).collect();
+    assert_eq!(values, vec![
+        lexer::TokenValue::Float(1000.0),
+        lexer::TokenValue::Float(0.025),
+        lexer::TokenValue::Float(-150.0),
+    ]);
+}
+
+#[test]
+fn lex_char_literals() {
+    let result = lexer::lex("'a' '\\n' '\\'' '\\x41' '\\u{263A}'".to_string()).unwrap();
+    let values: Vec<lexer::TokenValue> = result.into_iter().map(|t| t.value).collect();
+    assert_eq!(values, vec![
+        lexer::TokenValue::Integer(97),
+        lexer::TokenValue::Integer(10),
+        lexer::TokenValue::Integer(39),
+        lexer::TokenValue::Integer(65),
+        lexer::TokenValue::Integer(0x263A),
+    ]);
+}
+
+#[test]
+fn lex_unterminated_char_error() {
+    let result = lexer::lex("psh 'ab'".to_string());
+    assert_eq!(result.unwrap_err().message, "Unterminated character literal".to_string());
+}
+
+#[test]
+fn lex_string_escapes() {
+    let result = lexer::lex("\"\\x41\\u{1F600}\\t\\\"\\\\\"".to_string()).unwrap();
+    assert_eq!(result[0].value, lexer::TokenValue::String("A\u{1F600}\t\"\\".to_string()));
+
+    let result = lexer::lex("\"\\x41BC\\x0a1\"".to_string()).unwrap();
+    assert_eq!(result[0].value, lexer::TokenValue::String("ABC\n1".to_string()));
+    let err = lexer::lex("\"\\x4\"".to_string()).unwrap_err();
+    assert_eq!(err.message, "Invalid escape sequence: '\\x4\"'".to_string());
+    let err = lexer::lex("\"\\xff\"".to_string()).unwrap_err();
+    assert_eq!(err.message, "Invalid escape sequence: '\\xff', use '\\u{ff}' above 7f".to_string());
+}
+
+#[test]
+fn lex_invalid_unicode_escape_error() {
+    let result = lexer::lex("\"\\u{D800}\"".to_string());
+    assert_eq!(result.unwrap_err().message, "Invalid unicode escape: '\\u{D800}'".to_string());
+}
+
+fn temp_dir(name: &str, files: &[(&str, &str)]) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("zelkel-vm-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    for (file, code) in files {
+        std::fs::write(dir.join(file), code).unwrap();
+    }
+    dir
+}
+
+fn preprocess_file(path: &std::path::Path) -> Result<Vec<lexer::Token>, Error> {
+    let code = std::fs::read_to_string(path).unwrap();
+    preprocessor::preprocess(code, path.to_str().unwrap())
+}
+
+#[test]
+fn preprocess_include_remembers_file() {
+    let dir = temp_dir("include", &[
+        ("main.zvm", "%include \"lib.zvm\"\n@entry:\n    run @five\n    ret"),
+        ("lib.zvm", "@five:\n    psh 5\n    ret"),
+    ]);
+    let tokens = preprocess_file(&dir.join("main.zvm")).unwrap();
+    let parsed = parser::parse(tokens).unwrap();
+    let lib = dir.join("lib.zvm").to_str().unwrap().to_string();
+    let main = dir.join("main.zvm").to_str().unwrap().to_string();
+    assert_eq!(parsed.instrs[0].file, Some(lib));
+    assert_eq!(parsed.instrs[3].file, Some(main));
+    assert_eq!(evaluator::evaluate(parsed).unwrap(), (vec![], 5));
+}
+
+#[test]
+fn preprocess_include_guard() {
+    let dir = temp_dir("guard", &[
+        ("main.zvm", "%include \"a.zvm\"\n%include \"b.zvm\"\n@entry:\n    psh 0\n    ret"),
+        ("a.zvm", "%include \"b.zvm\"\n@a:\n    ret"),
+        ("b.zvm", "@b:\n    ret"),
+    ]);
+    let tokens = preprocess_file(&dir.join("main.zvm")).unwrap();
+    let parsed = parser::parse(tokens).unwrap();
+    assert_eq!(parsed.funcs.len(), 3);
+}
+
+#[test]
+fn preprocess_include_cycle_error() {
+    let dir = temp_dir("cycle", &[
+        ("main.zvm", "%include \"a.zvm\"\n@entry:\n    ret"),
+        ("a.zvm", "%include \"b.zvm\""),
+        ("b.zvm", "%include \"a.zvm\""),
+    ]);
+    let err = preprocess_file(&dir.join("main.zvm")).unwrap_err();
+    let a = dir.join("a.zvm").to_str().unwrap().to_string();
+    let b = dir.join("b.zvm").to_str().unwrap().to_string();
+    assert_eq!(err.message, format!("Include cycle: {} -> {} -> {}", a, b, a));
+    assert_eq!(err.file, Some(b));
+}
+
+#[test]
+fn preprocess_runtime_error_names_included_file() {
+    let dir = temp_dir("runtime", &[
+        ("main.zvm", "%include \"lib.zvm\"\n@entry:\n    run @broken"),
+        ("lib.zvm", "@broken:\n    add"),
+    ]);
+    let tokens = preprocess_file(&dir.join("main.zvm")).unwrap();
+    let err = evaluator::evaluate(parser::parse(tokens).unwrap()).unwrap_err();
+    let lib = dir.join("lib.zvm").to_str().unwrap().to_string();
+    assert_eq!(format!("{:?}", err), format!("Add: Stack underflow near {}:2:4", lib));
+}
+
+fn parse_str(code: &str) -> parser::ParserRet {
+    parser::parse(lexer::lex(code.to_string()).unwrap()).unwrap()
+}
+
+fn parse_library_str(code: &str) -> parser::ParserRet {
+    parser::parse_library(lexer::lex(code.to_string()).unwrap()).unwrap()
+}
+
+#[test]
+fn link_cross_module_call() {
+    let main = parse_str("@entry:\n    psh 4\n    run @math::double\n    ret\n.done:");
+    let math = parse_str("%module math\n%export @double\n@double:\n    run @twice\n    ret\n@twice:\n    dup\n    add\n    jmp .done\n.done:\n    ret");
+    let linked = linker::link(vec![main, math]).unwrap();
+    assert!(linked.funcs.contains_key("@math::twice"));
+    assert!(linked.labels.contains_key(".done"));
+    assert!(linked.labels.contains_key(".math::done"));
+    assert_eq!(linked.exports, vec!["@math::double".to_string()]);
+    assert_eq!(evaluator::evaluate(linked).unwrap(), (vec![], 8));
+}
+
+#[test]
+fn link_unexported_function_error() {
+    let main = parse_str("@entry:\n    run @math::twice\n    ret");
+    let math = parse_str("%module math\n@twice:\n    ret");
+    let err = linker::link(vec![main, math]).unwrap_err();
+    assert_eq!(err.message, "Function @math::twice is not exported".to_string());
+}
+
+#[test]
+fn link_unresolved_function_error() {
+    let main = parse_str("@entry:\n    run @missing\n    ret");
+    let err = linker::link(vec![main]).unwrap_err();
+    assert_eq!(err.message, "Unresolved function @missing".to_string());
+}
+
+#[test]
+fn link_duplicate_module_error() {
+    let main = parse_str("@entry:\n    ret");
+    let a = parse_str("%module math\n@a:\n    ret");
+    let b = parse_str("%module math\n@b:\n    ret");
+    let err = linker::link(vec![main, a, b]).unwrap_err();
+    assert_eq!(err.message, "Module math linked more than once".to_string());
+}
+
+#[test]
+fn link_duplicate_function_error() {
+    let main = parse_str("@entry:\n    ret\n@helper:\n    ret");
+    let other = parse_str("@entry:\n    ret");
+    let err = linker::link(vec![main, other]).unwrap_err();
+    assert_eq!(err.message, "Function @entry defined in multiple modules".to_string());
+}
+
+#[test]
+fn link_labels_are_local_to_files() {
+    let main = parse_str("@entry:\n    psh 1\n    jmp .done\n.done:\n    run @helper\n    ret");
+    let helper = parse_library_str("@helper:\n    jmp .done\n    psh 5\n.done:\n    psh 2\n    add\n    ret");
+    let linked = linker::link(vec![main, helper]).unwrap();
+    assert_eq!(evaluator::evaluate(linked).unwrap(), (vec![], 3));
+
+    let a = parse_library_str("@test_a:\n    jmp .end\n.end:\n    psh 0\n    ret");
+    let b = parse_library_str("@test_b:\n    jmp .end\n    psh 1\n    ret\n.end:\n    psh 0\n    ret");
+    let results = tester::run(&linker::link_library(vec![a, b]).unwrap(), "", |_| {});
+    assert!(results.iter().all(|r| r.failure.is_none()));
+
+    let main = parse_str(".data\n    alc *b, 4\n@entry:\n    psh 0\n    ret");
+    let other = parse_library_str(".data\n    alc *b, \"x\"\n@other:\n    ret");
+    let err = linker::link(vec![main, other]).unwrap_err();
+    assert_eq!(err.message, "Buffer *b defined in multiple modules".to_string());
+}
+
+#[test]
+fn parse_module_without_entry() {
+    let parsed = parse_str("%module math\n%export @one\n@one:\n    psh 1\n    ret");
+    assert_eq!(parsed.module, Some("math".to_string()));
+    assert_eq!(parsed.exports, vec!["@one".to_string()]);
+}
+
+#[test]
+fn parse_constants() {
+    let parsed = parse_str(".const SYS_WRITE = 1\n.const GREETING = \"hi\"\n.const ALIAS = SYS_WRITE\n@entry:\n    psh SYS_WRITE\n    psh GREETING\n    psh ALIAS");
+    let params: Vec<parser::ValueType> = parsed.instrs[1..].iter().map(|i| i.params[0].clone()).collect();
+    assert_eq!(params, vec![
+        parser::ValueType::Integer(1),
+        parser::ValueType::String("hi".to_string()),
+        parser::ValueType::Integer(1),
+    ]);
+}
+
+#[test]
+fn parse_unknown_constant_error() {
+    let tokens = lexer::lex("@entry:\n    alc *buf, SIZE".to_string()).unwrap();
+    assert_eq!(parser::parse(tokens).unwrap_err().message, "Constant SIZE not found".to_string());
+    let tokens = lexer::lex("@entry:\n    psh UNKNOWN".to_string()).unwrap();
+    assert_eq!(format!("{:?}", parser::parse(tokens).unwrap_err()), "Constant UNKNOWN not found near 2:8".to_string());
+}
+
+#[test]
+fn parse_duplicate_constant_error() {
+    let tokens = lexer::lex(".const A = 1\n.const A = 2\n@entry:".to_string()).unwrap();
+    assert_eq!(parser::parse(tokens).unwrap_err().message, "Constant A already exists".to_string());
+}
+
+#[test]
+fn parse_data_section_only_allows_alc() {
+    let tokens = lexer::lex(".data\n    psh 1\n@entry:".to_string()).unwrap();
+    assert_eq!(parser::parse(tokens).unwrap_err().message, "Only alc is allowed in the .data section, got psh".to_string());
+}
+
+#[test]
+fn evaluate_data_section() {
+    let parsed = parse_str(".const SIZE = 4\n.data\n    alc *msg, \"hello\"\n    alc *buf, SIZE\n.data:\n@entry:\n    psh *msg\n    typ str\n    psh *buf\n    len\n    pop $size\n    pop $_\n    psh $size\n    ret");
+    assert_eq!(parsed.data.len(), 2);
+    assert!(parsed.labels.contains_key(".data"));
+    assert_eq!(evaluator::evaluate(parsed).unwrap(), (vec![parser::ValueType::String("hello".to_string())], 4));
+}
+
+#[test]
+fn preprocess_macro_expansion() {
+    let code = "%macro countdown(n)\n    psh n\n.loop:\n    psh 1\n    sub\n    dup\n    jnz .loop\n    pop $_\n%endmacro\n@entry:\n    countdown(3)\n    countdown(2)\n    psh 0\n    ret";
+    let tokens = preprocessor::preprocess(code.to_string(), "macro.zvm").unwrap();
+    let parsed = parser::parse(tokens).unwrap();
+    assert!(parsed.labels.contains_key(".loop__countdown_1"));
+    assert!(parsed.labels.contains_key(".loop__countdown_2"));
+    assert!(parsed.instrs[1..].iter().take(7).all(|i| i.line == 11 && i.col == 4));
+    assert_eq!(evaluator::evaluate(parsed).unwrap(), (vec![], 0));
+}
+
+#[test]
+fn preprocess_nested_macros() {
+    let code = "%macro double(x)\n    psh x\n    psh x\n    add\n%endmacro\n%macro quadruple(x)\n    double(x)\n    double(x)\n    add\n%endmacro\n@entry:\n    quadruple(5)\n    ret";
+    let tokens = preprocessor::preprocess(code.to_string(), "macro.zvm").unwrap();
+    assert_eq!(evaluator::evaluate(parser::parse(tokens).unwrap()).unwrap(), (vec![], 20));
+}
+
+#[test]
+fn preprocess_macro_argument_count_error() {
+    let code = "%macro pair(a, b)\n    psh a\n    psh b\n%endmacro\n@entry:\n    pair(1)";
+    let err = preprocessor::preprocess(code.to_string(), "macro.zvm").unwrap_err();
+    assert_eq!(err.message, "Macro pair expects 2 arguments, got 1".to_string());
+    assert_eq!((err.line, err.col), (6, 4));
+}
+
+#[test]
+fn preprocess_unknown_macro_error() {
+    let err = preprocessor::preprocess("@entry:\n    missing()".to_string(), "macro.zvm").unwrap_err();
+    assert_eq!(err.message, "Macro missing not found".to_string());
+}
+
+#[test]
+fn preprocess_macro_expansion_limit() {
+    let mut code: String = (0..20).map(|i| format!("%macro m{}()\n    m{}()\n    m{}()\n%endmacro\n", i, i + 1, i + 1)).collect();
+    code.push_str("%macro m20()\n    psh 1\n%endmacro\n@entry:\n    m0()");
+    let err = preprocessor::preprocess(code, "macro.zvm").unwrap_err();
+    assert!(err.message.ends_with("exceeds the limit of 65536 expansions"), "{}", err.message);
+    assert_eq!((err.line, err.col), (85, 4));
+}
+
+#[test]
+fn preprocess_unterminated_macro_error() {
+    let err = preprocessor::preprocess("%macro open()\n    psh 1".to_string(), "macro.zvm").unwrap_err();
+    assert_eq!(err.message, "Unterminated macro, expected %endmacro".to_string());
+}
+
+#[test]
+fn lex_skips_comments() {
+    let result = lexer::lex("psh 1 ; push one\n; whole line \\\nadd".to_string()).unwrap();
+    let values: Vec<lexer::TokenValue> = result.into_iter().map(|t| t.value).collect();
+    assert_eq!(values, vec![
+        lexer::TokenValue::Identifier("psh".to_string()),
+        lexer::TokenValue::Integer(1),
+        lexer::TokenValue::Identifier("add".to_string()),
+    ]);
+}
+
+#[test]
+fn disasm_annotates_targets() {
+    let program = parse_str("@entry:\n    run @f\n    jmp .end\n.end:\n    ret\n@f:\n    ret");
+    let listing = disasm::disasm(&program);
+    let lines: Vec<&str> = listing.lines().map(|l| l.trim_end()).collect();
+    assert_eq!(lines[1], format!("{:<39} ; 1 -> 5", "    run @f"));
+    assert_eq!(lines[2], format!("{:<39} ; 2 -> 3", "    jmp .end"));
+}
+
+#[test]
+fn disasm_round_trip() {
+    let code = "%module lib\n%export @entry\n.const GREETING = \"tab\\there \\\"quoted\\\" \\u{7}\"\n.data\n    alc *msg, GREETING\n    alc *buf, 16\n@entry:\n    psh -1.5e-3\n    psh 'a'\n    psh true\n    psh GREETING <src/main.zk:1:2>\n    pop $x\n    psh $x\n    typ str\n.loop:\n    jzr .loop\n    psh *msg\n    dlc *buf\n    pop $\n    ret";
+    let program = parse_str(code);
+    let reparsed = parse_str(&disasm::disasm(&program));
+    assert_eq!(reparsed, program);
+}
+
+#[test]
+fn disasm_round_trip_leading_instructions() {
+    let code = "alc *early, 4\npsh *early\n.data\n    alc *msg, \"hi\"\n@entry:\n    psh *msg\n    psh 3.4028235e38\n    ret";
+    let program = parse_str(code);
+    let reparsed = parse_str(&disasm::disasm(&program));
+    assert_eq!(reparsed, program);
+    assert_eq!(reparsed.data.len(), 1);
+
+    let err = lexer::lex("psh 1e39".to_string()).unwrap_err();
+    assert_eq!(err.message, "Float out of range: '1e39'".to_string());
+    let err = lexer::lex("psh -1e39".to_string()).unwrap_err();
+    assert_eq!(err.message, "Float out of range: '-1e39'".to_string());
+}
+
+#[test]
+fn disasm_round_trip_linked() {
+    let main = parse_str("@entry:\n    psh 4\n    run @math::double\n    ret");
+    let math = parse_str("%module math\n%export @double\n@double:\n    dup\n    jzr .zero\n    dup\n    add\n.zero:\n    ret");
+    let linked = linker::link(vec![main, math]).unwrap();
+    let reparsed = parse_str(&disasm::disasm(&linked));
+    assert_eq!(reparsed, linked);
+}
+
+#[test]
+fn format_normalizes_layout() {
+    let code = "; header\n.const N=0x10\n@entry:\npsh   N   ; count\n  alc *buf,128\npsh \"a\\x41\"\n<src/main.zk:4:6>\n.loop:\npsh 'a'\n\n\n  jnz .loop\n   ; before f\n@f:\n  ret\n";
+    let expected = "; header\n.const N = 0x10\n@entry:\n    psh N ; count\n    alc *buf, 128\n    psh \"aA\" <src/main.zk:4:6>\n    .loop:\n        psh 'a'\n\n        jnz .loop\n; before f\n@f:\n    ret\n";
+    assert_eq!(formatter::format(code).unwrap(), expected.to_string());
+    assert_eq!(formatter::format(expected).unwrap(), expected.to_string());
+}
+
+#[test]
+fn format_macros_and_data() {
+    let code = "%macro pair( a,b )\npsh a\n    psh b\n%endmacro\n.data\nalc *msg , \"hi\"\n@entry:\n    pair( 1 , 2 )\n";
+    let expected = "%macro pair(a, b)\n    psh a\n    psh b\n%endmacro\n.data\n    alc *msg, \"hi\"\n@entry:\n    pair(1, 2)\n";
+    assert_eq!(formatter::format(code).unwrap(), expected.to_string());
+}
+
+#[test]
+fn format_keeps_numbers_after_multi_line_strings() {
+    let code = "@entry:\n    psh \"a\nb\" psh 0x10\n    ret\n";
+    let tokens = lexer::lex(code.to_string()).unwrap();
+    assert_eq!((tokens[4].line, tokens[4].col), (3, 3));
+    assert_eq!((tokens[5].line, tokens[5].col), (3, 7));
+    assert_eq!(formatter::format(code).unwrap(), "@entry:\n    psh \"a\\nb\"\n    psh 0x10\n    ret\n".to_string());
+}
+
+#[test]
+fn format_preserves_program() {
+    let code = std::fs::read_to_string("test.zvm").unwrap();
+    let formatted = formatter::format(&code).unwrap();
+    assert_eq!(parse_str(&formatted), parse_str(&code));
+}
+
+#[test]
+fn lint_reports_common_mistakes() {
+    let program = parse_str(".data\n    alc *msg, \"hi\"\n@entry:\n    alc *buf, 4\n    psh 1\n    pop $x\n    dlc *buf\n    jmp .end\n    psh 2\n.unused:\n.end:\n    psh *msg\n    ret\n@helper:\n    ret");
+    let warnings: Vec<(&str, usize)> = lint::lint(&program, &[]).iter().map(|w| (w.lint, w.line)).collect();
+    assert_eq!(warnings, vec![
+        ("unfreed-buffer", 2),
+        ("unused-variable", 6),
+        ("unreachable-code", 9),
+        ("unused-label", 10),
+        ("unused-function", 14),
+    ]);
+}
+
+#[test]
+fn lint_respects_allowed_lints() {
+    let program = parse_str("%module lib\n%export @api\n@api:\n    ret\n@helper:\n    ret\n.unused:");
+    let allowed = vec!["unused-label".to_string()];
+    let warnings: Vec<String> = lint::lint(&program, &allowed).iter().map(|w| w.message.clone()).collect();
+    assert_eq!(warnings, vec!["Function @helper is never used".to_string()]);
+}
+
+fn debug_session(code: &str, commands: &str) -> (String, Option<i32>) {
+    let program = linker::link(vec![parse_str(code)]).unwrap();
+    let mut vm = evaluator::Vm::new(program).unwrap();
+    let mut output: Vec<u8> = Vec::new();
+    let code = debugger::debug(&mut vm, commands.as_bytes(), &mut output).unwrap();
+    (String::from_utf8(output).unwrap(), code)
+}
+
+#[test]
+fn debug_step_off_the_end() {
+    let (output, code) = debug_session("@entry:\n    psh 1", "step\nstep\nwhere\nstep\nstep\n");
+    assert_eq!(output.matches("Program finished").count(), 2);
+    assert!(output.contains("Program exited with code 0"));
+    assert!(output.contains("The program is not running"));
+    assert_eq!(code, Some(0));
+}
+
+#[test]
+fn debug_breakpoints_and_inspection() {
+    let code = "@entry:\n    psh 1\n    pop $x\n    run @f\n    ret\n@f:\n    psh 2\n    <src/main.zk:3:1>\n    psh 3\n    ret";
+    let (output, code) = debug_session(code, "break @f\nbreak <src/main.zk:3:1>\ncontinue\nvars\nrets\ncontinue\nstep 2\nstack\ncontinue\n");
+    assert!(output.contains("Breakpoint 0 at @f (#6)"));
+    assert!(output.contains("Breakpoint @f (#6): #6 psh 2 at 7:4"));
+    assert!(output.contains("$x = 1"));
+    assert!(output.contains("#3 in @entry"));
+    assert!(output.contains("Breakpoint <src/main.zk:3:1>: #7 <src/main.zk:3:1> at 8:4"));
+    assert!(output.contains("[Integer(2), Integer(3)]"));
+    assert!(output.contains("Program exited with code 3"));
+    assert_eq!(code, Some(3));
+}
+
+#[test]
+fn debug_line_breakpoints_and_errors() {
+    let (output, code) = debug_session("@entry:\n    psh 1\n    add\n    ret", "break 3\nbreak .missing\ncontinue\nwhere\nstep\nstep\nquit\n");
+    assert!(output.contains("Breakpoint 3: #2 add at 3:4"));
+    assert!(output.contains(".missing not found"));
+    assert!(output.contains("Runtime error: Add: Stack underflow near 3:4"));
+    assert!(output.contains("The program is not running"));
+    assert_eq!(code, None);
+}
+
+#[test]
+fn trace_logs_each_instruction() {
+    let program = linker::link(vec![parse_str("@entry:\n    psh \"a\\n\"\n    <src/main.zk:2:3>\n    psh 0\n    ret")]).unwrap();
+    let mut vm = evaluator::Vm::new(program).unwrap();
+    let mut output: Vec<u8> = Vec::new();
+    assert_eq!(tracer::trace(&mut vm, false, &mut output).unwrap(), 0);
+    assert_eq!(String::from_utf8(output).unwrap(), "#0 @entry: at 1:0 [- -> -]\n#1 psh \"a\\n\" at 2:4 [- -> \"a\\n\"]\n#2 <src/main.zk:2:3> at 3:4 [\"a\\n\" -> \"a\\n\"]\n#3 psh 0 at 4:4 (src/main.zk:2:3) [\"a\\n\" -> 0]\n#4 ret at 5:4 (src/main.zk:2:3) [0 -> \"a\\n\"]\n");
+}
+
+#[test]
+fn trace_json_lines() {
+    let program = linker::link(vec![parse_str("@entry:\n    psh \"a\\n\"\n    <src/main.zk:2:3>\n    psh 0\n    ret")]).unwrap();
+    let mut vm = evaluator::Vm::new(program).unwrap();
+    let mut output: Vec<u8> = Vec::new();
+    tracer::trace(&mut vm, true, &mut output).unwrap();
+    let lines: Vec<String> = String::from_utf8(output).unwrap().lines().map(|l| l.to_string()).collect();
+    assert_eq!(lines[1], "{\"index\":1,\"instr\":\"psh \\\"a\\\\n\\\"\",\"file\":null,\"line\":2,\"col\":4,\"debug_symbol\":null,\"before\":null,\"after\":\"\\\"a\\\\n\\\"\"}");
+    assert_eq!(lines[3], "{\"index\":3,\"instr\":\"psh 0\",\"file\":null,\"line\":4,\"col\":4,\"debug_symbol\":\"src/main.zk:2:3\",\"before\":\"\\\"a\\\\n\\\"\",\"after\":0}");
+}
+
+#[test]
+fn profile_counts_instructions_functions_and_sources() {
+    let program = linker::link(vec![parse_str("@entry:\n    psh 2\n    pop $n\n    <src/main.zk:1:1>\n.loop:\n    run @f\n    psh $n\n    psh 1\n    sub\n    dup\n    pop $n\n    jnz .loop\n    psh 0\n    ret\n@f:\n    ret")]).unwrap();
+    let mut vm = evaluator::Vm::new(program).unwrap();
+    let (code, profile) = profiler::profile(&mut vm).unwrap();
+    assert_eq!(code, 0);
+    assert_eq!(profile.instrs[5].1.count, 2);
+    assert_eq!(profile.funcs["@entry"].count, 22);
+    assert_eq!(profile.funcs["@f"].count, 2);
+    assert_eq!(profile.sources["src/main.zk:1:1"].count, 20);
+    assert_eq!(profiler::folded(&profile), "@entry 22\n@entry;@f 2\n".to_string());
+    assert!(profiler::report(&profile).contains("@f\n"));
+}
+
+#[test]
+fn coverage_lcov_report() {
+    let program = linker::link(vec![parse_str("@entry:\n    psh 1\n    <src/main.zk:2:1>\n    jnz .yes\n    psh 5\n    ret\n.yes:\n    psh 0\n    jzr .end\n.end:\n    psh 0\n    ret\n@unused:\n    ret")]).unwrap();
+    let mut vm = evaluator::Vm::new(program).unwrap();
+    let (code, coverage) = coverage::cover(&mut vm).unwrap();
+    assert_eq!(code, 0);
+
+    let lcov = coverage::lcov(&vm.program, &coverage, "main.zvm");
+    let records: Vec<&str> = lcov.split("end_of_record\n").collect();
+    assert!(records[0].starts_with("TN:\nSF:main.zvm\nFN:1,@entry\nFN:13,@unused\nFNDA:1,@entry\nFNDA:0,@unused\nFNF:2\nFNH:1\n"));
+    assert!(records[0].contains("BRDA:4,3,0,1\nBRDA:4,3,1,0\nBRDA:9,8,0,1\nBRDA:9,8,1,0\nBRF:4\nBRH:2\n"));
+    assert!(records[0].contains("DA:5,0\nDA:6,0\nDA:8,1\n"));
+    assert!(records[0].ends_with("LF:10\nLH:7\n"));
+    assert_eq!(records[1], "TN:\nSF:src/main.zk\nFNF:0\nFNH:0\nBRDA:2,3,0,1\nBRDA:2,3,1,0\nBRDA:2,8,0,1\nBRDA:2,8,1,0\nBRF:4\nBRH:2\nDA:2,1\nLF:1\nLH:1\n");
+}
+
+#[test]
+fn coverage_counts_calls_of_functions_starting_with_a_loop() {
+    let program = linker::link(vec![parse_str("@entry:\n    psh 3\n    run @count\n    psh 2\n    run @count\n    ret\n@count:\n.loop:\n    psh 1\n    sub\n    dup\n    jnz .loop\n    ret")]).unwrap();
+    let mut vm = evaluator::Vm::new(program).unwrap();
+    let (_, coverage) = coverage::cover(&mut vm).unwrap();
+    let lcov = coverage::lcov(&vm.program, &coverage, "main.zvm");
+    assert!(lcov.contains("FNDA:1,@entry\nFNDA:2,@count\n"), "{}", lcov);
+}
+
+#[test]
+fn fuel_exhaustion_is_resumable() {
+    let program = linker::link(vec![parse_str("@entry:\n    psh 3\n.loop:\n    psh 1\n    sub\n    dup\n    jnz .loop\n    ret")]).unwrap();
+    let mut vm = evaluator::Vm::new(program).unwrap();
+    vm.fuel = Some(5);
+
+    let err = vm.run().unwrap_err();
+    assert_eq!(err.kind, ErrorKind::OutOfFuel);
+    assert_eq!((err.message.as_str(), err.line), ("Out of fuel", 6));
+    assert_eq!(vm.stack, vec![parser::ValueType::Integer(2)]);
+
+    vm.fuel = Some(100);
+    assert_eq!(vm.run().unwrap(), 0);
+    assert_eq!(vm.fuel, Some(87));
+}
+
+#[test]
+fn deadline_stops_infinite_loop() {
+    let program = linker::link(vec![parse_str("@entry:\n.loop:\n    jmp .loop")]).unwrap();
+    let mut vm = evaluator::Vm::new(program).unwrap();
+    vm.deadline = Some(std::time::Instant::now() + std::time::Duration::from_millis(10));
+    let err = vm.run().unwrap_err();
+    assert_eq!(err.kind, ErrorKind::Timeout);
+    assert_eq!(err.message, "Deadline exceeded".to_string());
+}
+
+fn run_with_limits(code: &str, limits: evaluator::Limits) -> Result<i32, Error> {
+    let mut vm = evaluator::Vm::new(linker::link(vec![parse_str(code)]).unwrap()).unwrap();
+    vm.limits = limits;
+    vm.run()
+}
+
+#[test]
+fn limits_are_enforced() {
+    let limits = evaluator::Limits { stack: 4, ret_stack: 2, vars: 1, buffer_bytes: 16, string_len: 8, items: 2, depth: 3 };
+    let cases = [
+        ("@entry:\n.loop:\n    psh 1\n    jmp .loop", "Stack limit of 4 values exceeded near 3:4"),
+        ("@entry:\n    run @entry", "Run: Return stack limit of 2 calls exceeded near 2:4"),
+        ("@entry:\n    psh 1\n    pop $a\n    psh 2\n    pop $a\n    psh 3\n    pop $b", "Pop: Variable limit of 1 exceeded near 7:4"),
+        ("@entry:\n    alc *a, 10\n    alc *b, 8", "Alc: Buffer limit exceeded, only 6 bytes left near 3:4"),
+        ("@entry:\n    psh \"abc\"\n    psh 3\n    mul", "String length limit of 8 exceeded near 4:4"),
+        ("@entry:\n    psh \"abcde\"\n    psh \"abcde\"\n    add", "String length limit of 8 exceeded near 4:4"),
+        ("@entry:\n    lnw\n    psh 100\n    lpu\n    psh 200\n    lpu\n    typ str", "String length limit of 8 exceeded near 7:4"),
+        ("@entry:\n    lnw\n    psh 1\n    lpu\n    psh 2\n    lpu\n    psh 3\n    lpu", "Lpu: Item limit of 2 exceeded near 8:4"),
+        ("@entry:\n    mnw\n    psh 1\n    psh 1\n    mst\n    psh 2\n    psh 2\n    mst\n    psh 2\n    psh 0\n    mst\n    psh 3\n    psh 3\n    mst", "Mst: Item limit of 2 exceeded near 14:4"),
+        ("@entry:\n    psh 0\n    arg", "String length limit of 8 exceeded near 3:4"),
+        ("@entry:\n    psh \"HOME\"\n    env", "String length limit of 8 exceeded near 3:4"),
+    ];
+
+    for (code, message) in cases {
+        let mut vm = evaluator::Vm::new(linker::link(vec![parse_str(code)]).unwrap()).unwrap();
+        vm.limits = limits;
+        vm.args = vec!["a long argument".to_string()];
+        vm.env.insert("HOME".to_string(), "/home/a/long/path".to_string());
+        let err = vm.run().unwrap_err();
+        assert_eq!(err.kind, ErrorKind::LimitExceeded);
+        assert_eq!(format!("{:?}", err), message.to_string());
+    }
+
+    let program = linker::link(vec![parse_str(".data\n    alc *big, 20\n@entry:\n    ret")]).unwrap();
+    let err = evaluator::Vm::with_limits(program, "@entry", limits).err().unwrap();
+    assert_eq!(err.kind, ErrorKind::LimitExceeded);
+    assert_eq!(format!("{:?}", err), "Alc: Buffer limit exceeded, only 16 bytes left near 2:4".to_string());
+
+    // Snapshots have to fit the limits of the VM they are restored into.
+    let program = linker::link(vec![parse_str("@entry:\n    psh 1\n    psh 2\n    psh \"a long string\"\n    ret")]).unwrap();
+    let mut vm = evaluator::Vm::new(program.clone()).unwrap();
+    for _ in 0..4 {
+        vm.step().unwrap();
+    }
+    let saved = snapshot::save(&vm);
+    let mut resumed = evaluator::Vm::new(program.clone()).unwrap();
+    resumed.limits = evaluator::Limits { stack: 2, ..evaluator::Limits::default() };
+    let err = snapshot::restore(&mut resumed, &saved).unwrap_err();
+    assert_eq!((err.kind, err.message), (ErrorKind::LimitExceeded, "Snapshot exceeds the stack limit of 2 values".to_string()));
+    resumed.limits = limits;
+    let err = snapshot::restore(&mut resumed, &saved).unwrap_err();
+    assert_eq!(err.message, "Snapshot exceeds the string length limit of 8".to_string());
+
+    let program = linker::link(vec![parse_str("@entry:\n    lnw\n    lnw\n    psh 1\n    lpu\n    psh 2\n    lpu\n    psh 3\n    lpu\n    lpu\n    ret")]).unwrap();
+    let mut vm = evaluator::Vm::new(program.clone()).unwrap();
+    for _ in 0..10 {
+        vm.step().unwrap();
+    }
+    let mut resumed = evaluator::Vm::new(program).unwrap();
+    resumed.limits = limits;
+    let err = snapshot::restore(&mut resumed, &snapshot::save(&vm)).unwrap_err();
+    assert_eq!(err.message, "Snapshot exceeds the item limit of 2".to_string());
+    resumed.limits = evaluator::Limits { depth: 1, ..evaluator::Limits::default() };
+    let err = snapshot::restore(&mut resumed, &snapshot::save(&vm)).unwrap_err();
+    assert_eq!((err.kind, err.message), (ErrorKind::LimitExceeded, "Snapshot exceeds the depth limit of 1".to_string()));
+}
+
+#[test]
+fn nesting_lists_stops_at_the_depth_limit() {
+    let code = "@entry:\n    lnw\n.loop:\n    pop $inner\n    lnw\n    psh $inner\n    lpu\n    jmp .loop";
+    let err = run_with_limits(code, evaluator::Limits::default()).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::LimitExceeded);
+    assert_eq!(format!("{:?}", err), "Lpu: Depth limit of 1024 exceeded near 7:4".to_string());
+
+    let code = "@entry:\n    mnw\n.loop:\n    pop $inner\n    mnw\n    psh \"a\"\n    psh $inner\n    mst\n    jmp .loop";
+    let err = run_with_limits(code, evaluator::Limits { depth: 8, ..evaluator::Limits::default() }).unwrap_err();
+    assert_eq!(format!("{:?}", err), "Mst: Depth limit of 8 exceeded near 8:4".to_string());
+}
+
+#[test]
+fn negative_sizes_are_errors() {
+    let err = run_with_limits("@entry:\n    alc *a, -1", evaluator::Limits::default()).unwrap_err();
+    assert_eq!(err.message, "Alc: Negative buffer size".to_string());
+    let err = run_with_limits("@entry:\n    psh \"a\"\n    psh -2\n    mul", evaluator::Limits::default()).unwrap_err();
+    assert_eq!(err.message, "Mul: Negative repeat count".to_string());
+}
+
+#[test]
+fn snapshot_round_trip_resumes_execution() {
+    let code = "@entry:\n    alc *b, \"hi\\n\"\n    psh 2.5\n    psh true\n    pop $flag\n    psh \"a\\\"b\"\n    <src/main.zk:1:2>\n    run @f\n    ret\n@f:\n    psh 7\n    psh 8\n    add\n    ret";
+    let mut vm = evaluator::Vm::new(linker::link(vec![parse_str(code)]).unwrap()).unwrap();
+    vm.fuel = Some(9);
+    assert_eq!(vm.run().unwrap_err().kind, ErrorKind::OutOfFuel);
+
+    let saved = snapshot::save(&vm);
+    assert!(saved.contains("ret 7\nstack 2.5\nstack \"a\\\"b\"\nstack 7\nvar $flag true\nbuf *b 68690a\n"));
+    assert!(saved.contains("debug_symbol <src/main.zk:1:2>\n"));
+
+    let mut resumed = evaluator::Vm::new(linker::link(vec![parse_str(code)]).unwrap()).unwrap();
+    snapshot::restore(&mut resumed, &saved).unwrap();
+    assert_eq!(snapshot::save(&resumed), saved);
+    assert_eq!(resumed.run().unwrap(), 15);
+    assert_eq!(resumed.stack, vec![parser::ValueType::Float(2.5), parser::ValueType::String("a\"b".to_string())]);
+}
+
+#[test]
+fn snapshot_rejects_other_programs() {
+    let vm = evaluator::Vm::new(linker::link(vec![parse_str("@entry:\n    psh 0\n    ret")]).unwrap()).unwrap();
+    let mut other = evaluator::Vm::new(linker::link(vec![parse_str("@entry:\n    psh 1\n    ret")]).unwrap()).unwrap();
+    let err = snapshot::restore(&mut other, &snapshot::save(&vm)).unwrap_err();
+    assert_eq!(format!("{:?}", err), "Snapshot was taken from a different program near 2:0".to_string());
+}
+
+#[test]
+fn snapshot_keeps_shared_lists_shared() {
+    let code = "@entry:\n    psh 0\n    pop $alias\n    pop $copy\n    psh $alias\n    psh 1\n    lpu\n    pop $_\n    psh 0\n    ret";
+    let mut vm = evaluator::Vm::new(parse_str(code)).unwrap();
+    while !vm.vars.contains_key("$alias") {
+        vm.step().unwrap();
+    }
+    let inner = parser::ValueType::List(std::rc::Rc::new(std::cell::RefCell::new(vec![parser::ValueType::Float(f32::NEG_INFINITY), parser::ValueType::String("-inf".to_string())])));
+    let outer = parser::ValueType::List(std::rc::Rc::new(std::cell::RefCell::new(vec![inner.clone(), inner])));
+    vm.stack.push(outer.clone());
+    vm.vars.insert("$alias".to_string(), outer);
+    vm.current_debug_symbol = Some(lexer::DebugSymbol { path: "my-inf.zvm".to_string(), line: 1, col: 2 });
+
+    let saved = snapshot::save(&vm);
+    assert!(saved.contains("stack ref0\nvar $alias ref0\nlist 0 [ref1, ref1]\nlist 1 [neg_inf, \"-inf\"]\n"));
+    let mut resumed = evaluator::Vm::new(vm.program.clone()).unwrap();
+    snapshot::restore(&mut resumed, &saved).unwrap();
+    assert_eq!(snapshot::save(&resumed), saved);
+    assert_eq!(resumed.current_debug_symbol, vm.current_debug_symbol);
+    assert_eq!(resumed.run().unwrap(), 0);
+    assert_eq!(resumed.vars["$alias"].to_string(), "[[-inf, \"-inf\"], [-inf, \"-inf\"], 1]".to_string());
+    assert_eq!(resumed.vars["$copy"], resumed.vars["$alias"]);
+
+    let cyclic = saved.replace("list 1 [neg_inf, \"-inf\"]", "list 1 [ref0]");
+    let err = snapshot::restore(&mut resumed, &cyclic).unwrap_err();
+    assert_eq!(err.message, "Snapshot has a list or map that contains itself".to_string());
+}
+
+#[test]
+fn repl_runs_lines_incrementally() {
+    let mut session = repl::Repl::new().unwrap();
+    assert_eq!(session.eval("psh 4 pop $x").unwrap(), None);
+    assert_eq!(session.eval("@double:").unwrap(), None);
+    assert!(session.defining());
+    session.eval("    psh 2 mul ret").unwrap();
+    session.eval("").unwrap();
+    assert!(!session.defining());
+    assert!(session.vm.stack.is_empty());
+
+    session.eval("psh $x run @double").unwrap();
+    assert_eq!(session.vm.stack, vec![parser::ValueType::Integer(8)]);
+    assert_eq!(format!("{:?}", session.eval("psh $y").unwrap_err()), "Variable $y not found near 5:0".to_string());
+    assert_eq!(session.eval("ret").unwrap(), Some(8));
+}
+
+#[test]
+fn repl_keeps_top_level_lines_out_of_functions() {
+    let mut session = repl::Repl::new().unwrap();
+    session.eval("@f:").unwrap();
+    session.eval("    psh 1").unwrap();
+    session.eval("").unwrap();
+    session.eval("psh 2").unwrap();
+    assert_eq!(session.vm.program.funcs["@f"], 2);
+
+    // Without a `ret`, @f runs off the end of the program instead of into later top-level lines.
+    assert_eq!(session.eval("run @f").unwrap(), Some(0));
+    assert_eq!(session.vm.stack, vec![parser::ValueType::Integer(2), parser::ValueType::Integer(1)]);
+    session.eval("@f:").unwrap();
+    session.eval("    ret").unwrap();
+    assert_eq!(format!("{:?}", session.eval("").unwrap_err()), "Function @f already exists near 5:0".to_string());
+}
+
+#[test]
+fn repl_session_output() {
+    let mut output: Vec<u8> = Vec::new();
+    repl::repl("psh 1\npsh 2\nadd\nsub\n:vars\npsh 5\npop $a\n:vars\n".as_bytes(), &mut output).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "zvm> [Integer(1)]\nzvm> [Integer(1), Integer(2)]\nzvm> [Integer(3)]\nzvm> Error: Sub: Stack underflow near 4:0\nzvm> zvm> [Integer(5)]\nzvm> []\nzvm> $a = 5\nzvm> ");
+}
+
+#[test]
+fn compiled_program_links_again() {
+    let main = parse_str(".data\n    alc *b, \"x\"\n@entry:\n    psh 7\n    run @math::sq\n    ret\n");
+    let math = parse_str("%module math\n%export @sq\n@sq:\n    dup\n    mul\n    ret\n");
+    let linked = linker::link(vec![main, math]).unwrap();
+
+    let compiled = disasm::disasm(&linked);
+    let relinked = linker::link(vec![parse_str(&compiled)]).unwrap();
+    assert_eq!(relinked, linked);
+    assert_eq!(evaluator::evaluate(relinked).unwrap(), (vec![], 49));
+}
+
+#[test]
+fn program_arguments_and_environment() {
+    let program = linker::link(vec![parse_str("@entry:\n    agc\n    psh 1\n    arg\n    psh \"HOME\"\n    env\n    psh \"MISSING\"\n    env\n    psh 5\n    arg")]).unwrap();
+    let mut vm = evaluator::Vm::new(program).unwrap();
+    vm.args = vec!["a".to_string(), "b".to_string()];
+    vm.env.insert("HOME".to_string(), "/home/z".to_string());
+
+    let err = vm.run().unwrap_err();
+    assert_eq!(format!("{:?}", err), "Arg: Index 5 out of range near 10:4".to_string());
+    assert_eq!(vm.stack, vec![
+        parser::ValueType::Integer(2),
+        parser::ValueType::String("b".to_string()),
+        parser::ValueType::String("/home/z".to_string()),
+        parser::ValueType::String("".to_string()),
+    ]);
+}
+
+#[test]
+fn alternative_entry_points() {
+    let code = "@helper:\n    psh 42\n    ret\n@main:\n    run @helper\n    ret";
+    let err = parser::parse(lexer::lex(code.to_string()).unwrap()).unwrap_err();
+    assert_eq!(err.message, "No @entry function found".to_string());
+
+    let library = parser::parse_library(lexer::lex(code.to_string()).unwrap()).unwrap();
+    assert_eq!(linker::link(vec![library.clone()]).unwrap_err().message, "No @entry function found".to_string());
+
+    let linked = linker::link_library(vec![library]).unwrap();
+    assert_eq!(evaluator::Vm::with_entry(linked.clone(), "@missing").err().unwrap().message, "Entry function @missing not found".to_string());
+    assert_eq!(evaluator::Vm::with_entry(linked, "@main").unwrap().run().unwrap(), 42);
+}
+
+#[test]
+fn test_runner() {
+    let math = parse_str("%module math\n%export @double\n@double:\n    dup\n    add\n    ret\n@test_double:\n    psh 4\n    run @double\n    psh 8\n    sub\n    ret\n@test_wrong:\n    psh 3\n    ret\n@test_crash:\n    add");
+    let program = linker::link_library(vec![math]).unwrap();
+    assert_eq!(tester::discover(&program), vec!["@math::test_double", "@math::test_wrong", "@math::test_crash"]);
+
+    let results = tester::run(&program, "", |_| {});
+    let failures: Vec<Option<String>> = results.iter().map(|r| r.failure.clone()).collect();
+    assert_eq!(failures, vec![None, Some("returned 3".to_string()), Some("Add: Stack underflow near 17:4".to_string())]);
+
+    let mut output = Vec::new();
+    assert!(!tester::report(&results, &mut output).unwrap());
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("test @math::test_wrong ... FAILED"));
+    assert!(output.contains("test result: FAILED. 1 passed; 2 failed;"));
+
+    let results = tester::run(&program, "double", |vm| vm.fuel = Some(100));
+    assert_eq!(results.len(), 1);
+    assert!(tester::report(&results, &mut Vec::new()).unwrap());
+}
+
+#[test]
+fn test_runner_runs_files_separately() {
+    let math = parse_library_str("%module math\n%export @double\n@double:\n    dup\n    add\n    ret\n@test_double:\n    psh 0\n    ret");
+    let a = parse_str("@entry:\n    ret\n@test_a:\n    psh 2\n    run @math::double\n.check:\n    psh 4\n    sub\n    ret");
+    let b = parse_str("@entry:\n    ret\n@test_b:\n    jmp .check\n.check:\n    psh 1\n    ret");
+    let results = tester::run_files(&[a, math, b], "", |_| {}).unwrap();
+    let names: Vec<&str> = results.iter().map(|r| r.name.as_str()).collect();
+    assert_eq!(names, vec!["@test_a", "@math::test_double", "@test_b"]);
+    let failures: Vec<Option<String>> = results.iter().map(|r| r.failure.clone()).collect();
+    assert_eq!(failures, vec![None, None, Some("returned 1".to_string())]);
+}
+
+#[test]
+fn assertions_and_panics() {
+    let code = "@entry:\n    psh 1\n    ast\n    run @check\n    ret\n@check:\n    psh 0\n    ast \"x > 0\"\n    pnc \"unreachable\"";
+    let err = evaluator::evaluate(parse_str(code)).unwrap_err();
+    assert_eq!(format!("{:?}", err), "Assertion failed: x > 0 near 8:4\n    at @check (8:4)\n    at @entry (4:4)".to_string());
+
+    let err = evaluator::evaluate(parse_str("@entry:\n    pnc \"bad state\"\n    psh \"x\"\n    ast")).unwrap_err();
+    assert_eq!(err.message, "Panic: bad state".to_string());
+    assert_eq!(err.backtrace(), ["@entry (2:4)".to_string()]);
+
+    let err = evaluator::evaluate(parse_str("@entry:\n    psh \"\"\n    ast")).unwrap_err();
+    assert_eq!(err.message, "Assertion failed".to_string());
+
+    let program = parse_str("@entry:\n    psh false\n    ast\n    pnc \"a\\\"b\"");
+    assert_eq!(parse_str(&disasm::disasm(&program)), program);
+}
+
+#[test]
+fn exception_handling() {
+    let code = "@entry:\n    psh 99\n    try .caught\n    run @risky\n    end\n    ret\n.caught:\n    try .thrown\n    psh 7\n    thr\n.thrown:\n    psh 0\n    ret\n@risky:\n    psh 1\n    psh \"x\"\n    add\n    ret";
+    let mut vm = evaluator::Vm::new(parse_str(code)).unwrap();
+    assert_eq!(vm.run().unwrap(), 0);
+    assert_eq!(vm.stack, vec![
+        parser::ValueType::Integer(99),
+        parser::ValueType::String("Invalid types for add String(\"x\") Integer(1)".to_string()),
+        parser::ValueType::Integer(7),
+    ]);
+    assert!(vm.ret_stack.is_empty() && vm.handlers.is_empty());
+
+    let err = evaluator::evaluate(parse_str("@entry:\n    psh \"boom\"\n    thr")).unwrap_err();
+    assert_eq!(format!("{:?}", err), "Uncaught exception: \"boom\" near 3:4".to_string());
+    let err = evaluator::evaluate(parse_str("@entry:\n    try .h\n    pnc \"fatal\"\n.h:\n    psh 0\n    ret")).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::Panic);
+    let err = evaluator::evaluate(parse_str("@entry:\n    end")).unwrap_err();
+    assert_eq!(err.message, "End: No active try".to_string());
+
+    // A handler set up in a function that returned is no longer active.
+    let code = "@entry:\n    run @f\n    psh 1\n    thr\n@f:\n    try .h\n    ret\n.h:\n    psh 2\n    ret";
+    let err = evaluator::evaluate(parse_str(code)).unwrap_err();
+    assert_eq!(err.message, "Uncaught exception: 1".to_string());
+
+    let mut vm = evaluator::Vm::new(parse_str("@entry:\n    try .h\n    psh 1\n    ret\n.h:\n    ret")).unwrap();
+    vm.step().unwrap();
+    vm.step().unwrap();
+    let mut resumed = evaluator::Vm::new(vm.program.clone()).unwrap();
+    snapshot::restore(&mut resumed, &snapshot::save(&vm)).unwrap();
+    assert_eq!(resumed.handlers, vec![evaluator::Handler { label: 4, stack: 0, calls: 0 }]);
+}
+
+#[test]
+fn integer_arithmetic_errors() {
+    for (code, message) in [
+        ("psh 1\n    psh 0\n    div", "Div: Division by zero"),
+        ("psh 1\n    psh 0\n    mod", "Mod: Division by zero"),
+        ("psh 2147483647\n    psh 1\n    add", "Add: Integer overflow"),
+        ("psh -2147483647\n    psh 2\n    sub", "Sub: Integer overflow"),
+        ("psh 65536\n    psh 65536\n    mul", "Mul: Integer overflow"),
+        ("psh -2147483647\n    psh 1\n    sub\n    psh -1\n    div", "Div: Integer overflow"),
+        ("psh -2147483647\n    psh 1\n    sub\n    psh -1\n    mod", "Mod: Integer overflow"),
+    ] {
+        let err = evaluator::evaluate(parse_str(&format!("@entry:\n    {}\n    ret", code))).unwrap_err();
+        assert_eq!(err.message, message.to_string());
+    }
+
+    let code = "@entry:\n    try .caught\n    psh 7\n    psh 0\n    div\n    ret\n.caught:\n    psh 0\n    ret";
+    let mut vm = evaluator::Vm::new(parse_str(code)).unwrap();
+    assert_eq!(vm.run().unwrap(), 0);
+    assert_eq!(vm.stack, vec![parser::ValueType::String("Div: Division by zero".to_string())]);
+}
+
+#[test]
+fn failed_syscall_pushes_errno() {
+    let code = format!("@entry:\n    psh -1\n    sys\n    pop $negative\n    psh 100000\n    sys\n    ern\n    pop $unknown\n    psh 4096\n    psh {}\n    sys\n    dup\n    ern\n    psh 2\n    ern\n    psh 100000\n    ern", syscalls::Sysno::close as i32);
+    let mut vm = evaluator::Vm::new(parse_str(&code)).unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.stack, vec![
+        parser::ValueType::Integer(-9),
+        parser::ValueType::String("EBADF".to_string()),
+        parser::ValueType::String("ENOENT".to_string()),
+        parser::ValueType::String("Unknown errno 100000".to_string()),
+    ]);
+    assert_eq!(vm.vars["$negative"], parser::ValueType::Integer(-38));
+    assert_eq!(vm.vars["$unknown"], parser::ValueType::String("ENOSYS".to_string()));
+}
+
+#[test]
+fn list_values() {
+    let code = "@entry:\n    lnw\n    dup\n    pop $alias\n    psh 1\n    lpu\n    psh \"a\"\n    lpu\n    psh $alias\n    psh true\n    lpu\n    pop $_\n    len\n    pop $n\n    psh 0\n    psh 9\n    lst\n    psh 1\n    lgt\n    pop $item\n    psh 1\n    psh 3\n    lsl\n    typ str\n    pop $s\n    lpo\n    pop $last\n    psh $alias\n    cmp\n    ret";
+    let mut vm = evaluator::Vm::new(parse_str(code)).unwrap();
+    assert_eq!(vm.run().unwrap(), 1);
+    assert_eq!(vm.vars["$n"], parser::ValueType::Integer(3));
+    assert_eq!(vm.vars["$item"], parser::ValueType::String("a".to_string()));
+    assert_eq!(vm.vars["$s"], parser::ValueType::String("[\"a\", true]".to_string()));
+    assert_eq!(vm.vars["$last"], parser::ValueType::Boolean(true));
+    assert_eq!(vm.vars["$alias"].to_string(), "[9, \"a\"]".to_string());
+
+    let err = evaluator::evaluate(parse_str("@entry:\n    lnw\n    psh 0\n    lgt")).unwrap_err();
+    assert_eq!(err.message, "Lgt: Index 0 out of range".to_string());
+    let err = evaluator::evaluate(parse_str("@entry:\n    lnw\n    psh 1\n    psh 0\n    lsl")).unwrap_err();
+    assert_eq!(err.message, "Lsl: Range 1..0 out of range".to_string());
+    let err = evaluator::evaluate(parse_str("@entry:\n    lnw\n    dup\n    lpu")).unwrap_err();
+    assert_eq!(err.message, "Lpu: Cannot add a list to itself".to_string());
+    let err = evaluator::evaluate(parse_str("@entry:\n    lnw\n    dup\n    pop $outer\n    lnw\n    dup\n    pop $inner\n    lpu\n    pop $_\n    psh $inner\n    psh $outer\n    lpu")).unwrap_err();
+    assert_eq!(err.message, "Lpu: Cannot add a list to itself".to_string());
+    let err = evaluator::evaluate(parse_str("@entry:\n    lnw\n    dup\n    pop $outer\n    lnw\n    lpu\n    psh 0\n    lgt\n    psh 0\n    psh $outer\n    lst")).unwrap_err();
+    assert_eq!(err.message, "Lst: Cannot add a list to itself".to_string());
+    let err = evaluator::evaluate(parse_str("@entry:\n    psh 1\n    lpo")).unwrap_err();
+    assert_eq!(err.message, "Lpo: Expected a list".to_string());
+
+    let mut vm = evaluator::Vm::new(parse_str("@entry:\n    lnw\n    lnw\n    psh 2.5\n    lpu\n    lpu\n    psh \"x\"\n    lpu\n    ret")).unwrap();
+    for _ in 0..7 {
+        vm.step().unwrap();
+    }
+    let mut resumed = evaluator::Vm::new(vm.program.clone()).unwrap();
+    snapshot::restore(&mut resumed, &snapshot::save(&vm)).unwrap();
+    assert_eq!(resumed.stack, vm.stack);
+}
+
+#[test]
+fn map_values() {
+    let code = "@entry:\n    mnw\n    psh \"b\"\n    psh 2\n    mst\n    psh 1\n    lnw\n    mst\n    psh \"a\"\n    psh true\n    mst\n    psh \"b\"\n    psh 0\n    mgt\n    pop $b\n    psh \"zz\"\n    psh -1\n    mgt\n    pop $missing\n    psh 1\n    mrm\n    psh 1\n    mhs\n    pop $has\n    mks\n    pop $keys\n    len\n    pop $n\n    typ str\n    pop $s\n    psh 0\n    ret";
+    let mut vm = evaluator::Vm::new(parse_str(code)).unwrap();
+    assert_eq!(vm.run().unwrap(), 0);
+    assert_eq!(vm.vars["$s"], parser::ValueType::String("{\"a\": true, \"b\": 2}".to_string()));
+    assert_eq!(vm.vars["$b"], parser::ValueType::Integer(2));
+    assert_eq!(vm.vars["$missing"], parser::ValueType::Integer(-1));
+    assert_eq!(vm.vars["$has"], parser::ValueType::Boolean(false));
+    assert_eq!(vm.vars["$keys"].to_string(), "[\"a\", \"b\"]".to_string());
+    assert_eq!(vm.vars["$n"], parser::ValueType::Integer(2));
+
+    let err = evaluator::evaluate(parse_str("@entry:\n    mnw\n    psh 1.5\n    psh 0\n    mst")).unwrap_err();
+    assert_eq!(err.message, "Mst: Invalid key type".to_string());
+    let err = evaluator::evaluate(parse_str("@entry:\n    lnw\n    mks")).unwrap_err();
+    assert_eq!(err.message, "Mks: Expected a map".to_string());
+    let err = evaluator::evaluate(parse_str("@entry:\n    mnw\n    dup\n    pop $outer\n    psh 1\n    lnw\n    dup\n    pop $inner\n    mst\n    pop $_\n    psh $inner\n    psh $outer\n    lpu")).unwrap_err();
+    assert_eq!(err.message, "Lpu: Cannot add a list to itself".to_string());
+    let err = evaluator::evaluate(parse_str("@entry:\n    mnw\n    dup\n    pop $outer\n    mnw\n    dup\n    pop $inner\n    psh 1\n    rot\n    mst\n    pop $_\n    psh $inner\n    psh 1\n    psh $outer\n    mst")).unwrap_err();
+    assert_eq!(err.message, "Mst: Cannot add a map to itself".to_string());
+
+    let mut vm = evaluator::Vm::new(parse_str("@entry:\n    mnw\n    psh 3\n    lnw\n    mst\n    psh \"k\"\n    psh \"v\"\n    mst\n    ret")).unwrap();
+    for _ in 0..8 {
+        vm.step().unwrap();
+    }
+    assert_eq!(vm.stack[0].to_string(), "{3: [], \"k\": \"v\"}".to_string());
+    let mut resumed = evaluator::Vm::new(vm.program.clone()).unwrap();
+    snapshot::restore(&mut resumed, &snapshot::save(&vm)).unwrap();
+    assert_eq!(resumed.stack, vm.stack);
+}
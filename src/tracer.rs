@@ -0,0 +1,80 @@
+use std::io::Write;
+use crate::Error;
+use crate::disasm;
+use crate::evaluator::Vm;
+use crate::parser::ValueType;
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn json_value(value: Option<&ValueType>) -> String {
+    match value {
+        Some(ValueType::Integer(i)) => i.to_string(),
+        Some(ValueType::Float(f)) if f.is_finite() => format!("{:?}", f),
+        Some(ValueType::Boolean(b)) => b.to_string(),
+        Some(value) => json_string(&disasm::literal(value)),
+        None => "null".to_string(),
+    }
+}
+
+fn text_value(value: Option<&ValueType>) -> String {
+    value.map(disasm::literal).unwrap_or("-".to_string())
+}
+
+// Runs `vm` to completion, writing one line per executed instruction with its index, source position,
+// the debug symbol in effect and the top of the stack before and after it. With `json` every line is
+// a JSON object instead.
+pub fn trace<W: Write>(vm: &mut Vm, json: bool, output: &mut W) -> Result<i32, Error> {
+    loop {
+        let index = vm.cur;
+        let Some(instr) = vm.program.instrs.get(index).cloned() else {
+            return Ok(vm.step()?.unwrap_or(0));
+        };
+        let debug_symbol = vm.current_debug_symbol.clone();
+        let before = vm.stack.last().cloned();
+
+        let exit = vm.step()?;
+        let after = vm.stack.last();
+
+        let line = if json {
+            format!(
+                "{{\"index\":{},\"instr\":{},\"file\":{},\"line\":{},\"col\":{},\"debug_symbol\":{},\"before\":{},\"after\":{}}}",
+                index,
+                json_string(&disasm::render(&instr)),
+                instr.file.as_deref().map(json_string).unwrap_or("null".to_string()),
+                instr.line,
+                instr.col,
+                debug_symbol.map(|ds| json_string(&format!("{}:{}:{}", ds.path, ds.line, ds.col))).unwrap_or("null".to_string()),
+                json_value(before.as_ref()),
+                json_value(after),
+            )
+        } else {
+            let location = match &instr.file {
+                Some(file) => format!("{}:{}:{}", file, instr.line, instr.col),
+                None => format!("{}:{}", instr.line, instr.col),
+            };
+            let source = debug_symbol.map(|ds| format!(" ({}:{}:{})", ds.path, ds.line, ds.col)).unwrap_or_default();
+            format!("#{} {} at {}{} [{} -> {}]", index, disasm::render(&instr), location, source, text_value(before.as_ref()), text_value(after))
+        };
+
+        writeln!(output, "{}", line).map_err(|e| Error::new(format!("Failed to write the trace: {}", e), instr.line, instr.col, &None))?;
+
+        if let Some(code) = exit {
+            return Ok(code);
+        }
+    }
+}